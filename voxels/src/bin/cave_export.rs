@@ -0,0 +1,464 @@
+//! Writes generated cave chunks to a glTF or OBJ file.
+//!
+//! ```text
+//! cargo run --bin cave_export -- --seed 42 --lod 0 --from -2,-1,-2 --to 2,1,2 --out cave.gltf
+//! ```
+
+use std::{env, fmt::Write as _, fs, path::PathBuf, process, sync::Arc};
+
+use bevy::{asset::AssetPlugin, prelude::*, render::mesh::VertexAttributeValues};
+use serde_json::json;
+use voxels::cave::{
+    chunk::{self, CaveChunk, CaveChunkSettings, CaveMeshMode, CaveNoiseKind},
+    generator::{CaveGenerator, CaveGeneratorPlugin, CaveGenerators},
+    material::{CaveMaterial, CaveMaterialPlugin},
+    mesh, pbr, voxelize,
+};
+
+const USAGE: &str = "usage: cave_export [options] --out <file.gltf|file.obj>
+
+options:
+    --seed <i32>            noise seed
+    --threshold <f32>       density above which samples are solid
+    --frequency <f32>       noise frequency
+    --noise <kind>          fbm, ridge, turbulence or cellular
+    --octaves <u8>          noise octaves
+    --generator <name>      registered generator that fills chunks
+    --mesh <mode>           blocky or smooth
+    --chunk-size <f32>      size of a lod 0 chunk
+    --lod <u32>             lod of the exported chunks
+    --subdivisions <u32>    voxels per chunk axis are 2^subdivisions
+    --from <x,y,z>          first chunk coordinate, inclusive
+    --to <x,y,z>            last chunk coordinate, inclusive";
+
+struct Options {
+    settings: CaveChunkSettings,
+    /// Materials of `settings.palette`, in the same order.
+    materials: Vec<CaveMaterial>,
+    generator: Arc<dyn CaveGenerator>,
+    subdivisions: u32,
+    from: IVec3,
+    to: IVec3,
+    out: PathBuf,
+}
+
+/// Submeshes of one chunk, by palette id, with the transform
+/// `pbr::insert_cave_chunk_pbr` would give them.
+struct ExportedChunk {
+    coord: IVec3,
+    transform: Transform,
+    submeshes: Vec<(u8, Submesh)>,
+}
+
+struct Submesh {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    let chunks = export_chunks(&options);
+    let result = match options.out.extension().and_then(|e| e.to_str()) {
+        Some("obj") => write_obj(&options, &chunks),
+        _ => write_gltf(&options, &chunks),
+    };
+    if let Err(err) = result {
+        eprintln!("failed to write {}: {err}", options.out.display());
+        process::exit(1);
+    }
+
+    let triangles: usize = chunks
+        .iter()
+        .flat_map(|chunk| &chunk.submeshes)
+        .map(|(_, submesh)| submesh.indices.len() / 3)
+        .sum();
+    println!(
+        "wrote {} chunks, {} triangles to {}",
+        chunks.len(),
+        triangles,
+        options.out.display()
+    );
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut settings = CaveChunkSettings::default();
+    let mut chunk_size = 1.28;
+    let mut lod = 0;
+    let mut subdivisions = 5;
+    let mut from = IVec3::splat(-1);
+    let mut to = IVec3::splat(1);
+    let mut out = None;
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        let number = |value: &str| {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("bad value for {flag}: {value}"))
+        };
+        match flag.as_str() {
+            "--seed" => settings.seed = number(&value)? as i32,
+            "--threshold" => settings.threshold = number(&value)? as f32,
+            "--frequency" => settings.frequency = number(&value)? as f32,
+            "--octaves" => settings.octaves = number(&value)? as u8,
+            "--generator" => settings.generator = value,
+            "--noise" => {
+                settings.noise = match value.as_str() {
+                    "fbm" => CaveNoiseKind::Fbm,
+                    "ridge" => CaveNoiseKind::Ridge,
+                    "turbulence" => CaveNoiseKind::Turbulence,
+                    "cellular" => CaveNoiseKind::Cellular,
+                    _ => return Err(format!("unknown noise kind: {value}")),
+                }
+            }
+            "--mesh" => {
+                settings.mesh_mode = match value.as_str() {
+                    "blocky" => CaveMeshMode::Blocky,
+                    "smooth" => CaveMeshMode::Smooth,
+                    _ => return Err(format!("unknown mesh mode: {value}")),
+                }
+            }
+            "--chunk-size" => chunk_size = number(&value)? as f32,
+            "--lod" => lod = number(&value)? as u32,
+            "--subdivisions" => subdivisions = number(&value)? as u32,
+            "--from" => from = parse_coord(&value)?,
+            "--to" => to = parse_coord(&value)?,
+            "--out" => out = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown option: {flag}")),
+        }
+    }
+
+    let scale = 2_i32
+        .checked_pow(lod)
+        .ok_or_else(|| format!("bad value for --lod: {lod}"))?;
+    settings.size = chunk_size * scale as f32;
+    let (materials, generator) = resolve(&mut settings)?;
+    Ok(Options {
+        settings,
+        materials,
+        generator,
+        subdivisions,
+        from: from.min(to),
+        to: from.max(to),
+        out: out.ok_or("missing --out")?,
+    })
+}
+
+/// Fills in the palette and looks up the generator through the same plugins
+/// the app uses, so the export matches what the app would show.
+fn resolve(
+    settings: &mut CaveChunkSettings,
+) -> Result<(Vec<CaveMaterial>, Arc<dyn CaveGenerator>), String> {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        AssetPlugin::default(),
+        CaveMaterialPlugin,
+        CaveGeneratorPlugin,
+    ))
+    .insert_resource(settings.clone());
+    chunk::insert_settings(&mut app.world);
+
+    *settings = app.world.remove_resource::<CaveChunkSettings>().unwrap();
+    let generator = app
+        .world
+        .resource::<CaveGenerators>()
+        .get(&settings.generator)
+        .ok_or_else(|| format!("unknown generator: {}", settings.generator))?;
+    let assets = app.world.resource::<Assets<CaveMaterial>>();
+    let materials = settings
+        .palette
+        .iter()
+        .map(|entry| {
+            assets
+                .get(&entry.material)
+                .cloned()
+                .ok_or_else(|| format!("missing material for {}", entry.name))
+        })
+        .collect::<Result<_, _>>()?;
+
+    // Strong handles need the app's asset channels, which go away with it.
+    for entry in &mut settings.palette {
+        entry.material = entry.material.clone_weak();
+    }
+    Ok((materials, generator))
+}
+
+fn parse_coord(value: &str) -> Result<IVec3, String> {
+    let coord: Vec<i32> = value
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("bad chunk coordinate: {value}"))?;
+    match coord[..] {
+        [x, y, z] => Ok(IVec3::new(x, y, z)),
+        _ => Err(format!("bad chunk coordinate: {value}")),
+    }
+}
+
+/// Runs every chunk in the region through the same voxelize and mesh stages
+/// as the app.
+fn export_chunks(options: &Options) -> Vec<ExportedChunk> {
+    let mut chunks = Vec::new();
+    for z in options.from.z..=options.to.z {
+        for y in options.from.y..=options.to.y {
+            for x in options.from.x..=options.to.x {
+                let coord = IVec3::new(x, y, z);
+                let origin = coord.as_vec3() * options.settings.size;
+                let cave_chunk = CaveChunk::generate(
+                    &*options.generator,
+                    &options.settings,
+                    origin,
+                    options.subdivisions,
+                    0,
+                );
+                let submeshes = voxelize::voxelize(&cave_chunk)
+                    .and_then(|voxels| mesh::mesh(&cave_chunk, &voxels))
+                    .expect("freshly generated chunks are not shared");
+
+                let transform = Transform::from_translation(origin)
+                    .mul_transform(pbr::voxel_transform(&cave_chunk))
+                    .mul_transform(Transform::from_translation(pbr::SUBMESH_OFFSET));
+                let mut submeshes: Vec<_> = submeshes
+                    .into_iter()
+                    .filter_map(|(id, mesh)| Some((id, submesh(&mesh)?)))
+                    .collect();
+                submeshes.sort_by_key(|(id, _)| *id);
+
+                chunks.push(ExportedChunk {
+                    coord,
+                    transform,
+                    submeshes,
+                });
+            }
+        }
+    }
+    chunks
+}
+
+fn submesh(mesh: &Mesh) -> Option<Submesh> {
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+        VertexAttributeValues::Float32x3(positions) => positions.clone(),
+        _ => return None,
+    };
+    let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL)? {
+        VertexAttributeValues::Float32x3(normals) => normals.clone(),
+        _ => return None,
+    };
+    let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR)? {
+        VertexAttributeValues::Float32x4(colors) => colors.clone(),
+        _ => return None,
+    };
+    let indices = mesh.indices()?.iter().map(|i| i as u32).collect();
+    Some(Submesh {
+        positions,
+        normals,
+        colors,
+        indices,
+    })
+}
+
+fn material_name(options: &Options, id: u8) -> &str {
+    options
+        .settings
+        .palette_entry(id)
+        .map_or("empty", |entry| entry.name.as_str())
+}
+
+/// One node per chunk, one primitive per palette id, with the vertex data in
+/// a `.bin` file next to the `.gltf`.
+fn write_gltf(options: &Options, chunks: &[ExportedChunk]) -> std::io::Result<()> {
+    let bin_path = options.out.with_extension("bin");
+    let mut bin: Vec<u8> = Vec::new();
+    let mut buffer_views = Vec::new();
+    let mut accessors = Vec::new();
+    let mut meshes = Vec::new();
+    let mut nodes = Vec::new();
+
+    let mut push_view = |bin: &mut Vec<u8>, bytes: &[u8], target: u32| {
+        let offset = bin.len();
+        bin.extend_from_slice(bytes);
+        bin.resize(bin.len().next_multiple_of(4), 0);
+        buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": bytes.len(),
+            "target": target,
+        }));
+        buffer_views.len() - 1
+    };
+
+    for chunk in chunks {
+        let mut primitives = Vec::new();
+        for (id, submesh) in &chunk.submeshes {
+            let (min, max) = submesh.positions.iter().fold(
+                (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                |(min, max), p| (min.min(Vec3::from(*p)), max.max(Vec3::from(*p))),
+            );
+
+            let view = push_view(
+                &mut bin,
+                &f32_bytes(submesh.positions.iter().flatten()),
+                34962,
+            );
+            accessors.push(json!({
+                "bufferView": view,
+                "componentType": 5126,
+                "count": submesh.positions.len(),
+                "type": "VEC3",
+                "min": min.to_array(),
+                "max": max.to_array(),
+            }));
+            let view = push_view(
+                &mut bin,
+                &f32_bytes(submesh.normals.iter().flatten()),
+                34962,
+            );
+            accessors.push(json!({
+                "bufferView": view,
+                "componentType": 5126,
+                "count": submesh.normals.len(),
+                "type": "VEC3",
+            }));
+            let view = push_view(&mut bin, &f32_bytes(submesh.colors.iter().flatten()), 34962);
+            accessors.push(json!({
+                "bufferView": view,
+                "componentType": 5126,
+                "count": submesh.colors.len(),
+                "type": "VEC4",
+            }));
+            let indices: Vec<u8> = submesh
+                .indices
+                .iter()
+                .flat_map(|i| i.to_le_bytes())
+                .collect();
+            let view = push_view(&mut bin, &indices, 34963);
+            accessors.push(json!({
+                "bufferView": view,
+                "componentType": 5125,
+                "count": submesh.indices.len(),
+                "type": "SCALAR",
+            }));
+
+            let n = accessors.len();
+            primitives.push(json!({
+                "attributes": { "POSITION": n - 4, "NORMAL": n - 3, "COLOR_0": n - 2 },
+                "indices": n - 1,
+                "material": id.saturating_sub(1),
+            }));
+        }
+
+        let mut node = json!({
+            "name": format!("chunk {} {} {}", chunk.coord.x, chunk.coord.y, chunk.coord.z),
+            "translation": chunk.transform.translation.to_array(),
+            "scale": chunk.transform.scale.to_array(),
+        });
+        if !primitives.is_empty() {
+            meshes.push(json!({ "primitives": primitives }));
+            node["mesh"] = json!(meshes.len() - 1);
+        }
+        nodes.push(node);
+    }
+
+    let materials: Vec<_> = options
+        .settings
+        .palette
+        .iter()
+        .zip(&options.materials)
+        .map(|(entry, material)| {
+            json!({
+                "name": entry.name,
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": material.base_color.as_linear_rgba_f32(),
+                    "metallicFactor": material.metallic,
+                    "roughnessFactor": material.perceptual_roughness,
+                },
+            })
+        })
+        .collect();
+
+    let mut gltf = json!({
+        "asset": { "version": "2.0", "generator": "voxels cave_export" },
+        "scene": 0,
+        "scenes": [{ "nodes": (0..nodes.len()).collect::<Vec<_>>() }],
+        "nodes": nodes,
+        "meshes": meshes,
+        "materials": materials,
+        "accessors": accessors,
+        "bufferViews": buffer_views,
+        "buffers": [{
+            "uri": bin_path.file_name().unwrap().to_string_lossy(),
+            "byteLength": bin.len(),
+        }],
+    });
+
+    // glTF doesn't allow empty arrays, which an all rock region would have.
+    gltf.as_object_mut()
+        .unwrap()
+        .retain(|_, value| value.as_array().is_none_or(|array| !array.is_empty()));
+
+    fs::write(&bin_path, bin)?;
+    fs::write(&options.out, serde_json::to_string_pretty(&gltf)?)
+}
+
+/// One object per chunk, one material group per palette id, with vertices
+/// already transformed since OBJ has no node transforms.
+fn write_obj(options: &Options, chunks: &[ExportedChunk]) -> std::io::Result<()> {
+    let mtl_path = options.out.with_extension("mtl");
+    let mut obj = String::new();
+    let mut mtl = String::new();
+
+    writeln!(
+        obj,
+        "mtllib {}",
+        mtl_path.file_name().unwrap().to_string_lossy()
+    )
+    .unwrap();
+    let mut base = 1;
+    for chunk in chunks {
+        writeln!(
+            obj,
+            "o chunk_{}_{}_{}",
+            chunk.coord.x, chunk.coord.y, chunk.coord.z
+        )
+        .unwrap();
+        let matrix = chunk.transform.compute_matrix();
+        for (id, submesh) in &chunk.submeshes {
+            writeln!(obj, "usemtl {}", material_name(options, *id)).unwrap();
+            for (p, n) in submesh.positions.iter().zip(&submesh.normals) {
+                let p = matrix.transform_point3(Vec3::from(*p));
+                let n = Vec3::from(*n);
+                writeln!(obj, "v {} {} {}", p.x, p.y, p.z).unwrap();
+                writeln!(obj, "vn {} {} {}", n.x, n.y, n.z).unwrap();
+            }
+            for triangle in submesh.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| triangle[i] as usize + base);
+                writeln!(obj, "f {a}//{a} {b}//{b} {c}//{c}").unwrap();
+            }
+            base += submesh.positions.len();
+        }
+    }
+
+    for (entry, material) in options.settings.palette.iter().zip(&options.materials) {
+        let [r, g, b, _] = material.base_color.as_rgba_f32();
+        writeln!(mtl, "newmtl {}\nKd {r} {g} {b}\n", entry.name).unwrap();
+    }
+
+    fs::write(&mtl_path, mtl)?;
+    fs::write(&options.out, obj)
+}
+
+fn f32_bytes<'a>(values: impl Iterator<Item = &'a f32>) -> Vec<u8> {
+    values.flat_map(|v| v.to_le_bytes()).collect()
+}
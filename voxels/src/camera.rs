@@ -1,28 +1,66 @@
 use std::f32::consts::PI;
 
-use bevy::{input::mouse::MouseMotion, math::Vec2Swizzles, prelude::*};
+use bevy::prelude::*;
+
+use crate::controls::{Action, ActionState};
+
+use self::rig::CameraMode;
+
+pub mod rig;
 
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
         app.register_type::<CameraControlSettings>()
-            .insert_resource(CameraControlSettings {
-                rotate_sensitivity: 0.002,
-                move_speed: 0.05,
-                sprint_factor: 10.0,
-            })
+            .register_type::<CameraController>()
+            .insert_resource(CameraControlSettings::default())
             .add_event::<CameraControlEvent>()
-            .add_systems(Update, input)
+            .add_plugins(rig::CameraRigPlugin)
+            .add_systems(
+                Update,
+                input.run_if(resource_exists::<ActionState>()).run_if(
+                    in_state(CameraMode::FirstPerson).or_else(in_state(CameraMode::ThirdPerson)),
+                ),
+            )
             .add_systems(Update, control);
     }
 }
 
-#[derive(Resource, Reflect, Debug, Default)]
+/// Longest frame simulated in one go, so hitches don't fling the camera.
+const MAX_DELTA_SECONDS: f32 = 0.1;
+
+#[derive(Resource, Reflect, Debug)]
 pub struct CameraControlSettings {
+    /// Radians per pixel of mouse movement.
     pub rotate_sensitivity: f32,
-    pub move_speed: f32,
+    /// Radians per second turned by look buttons and sticks.
+    pub look_speed: f32,
+    /// Seconds for turns to get about two thirds of the way there, 0 turning
+    /// at once.
+    pub look_smoothing: f32,
+    /// Units per second when flying without sprinting.
+    pub max_speed: f32,
     pub sprint_factor: f32,
+    /// Units per second squared towards the wished velocity.
+    pub acceleration: f32,
+    /// Exponential rate per second the velocity decays at once there is no
+    /// input.
+    pub damping: f32,
+}
+
+impl Default for CameraControlSettings {
+    fn default() -> Self {
+        Self {
+            rotate_sensitivity: 0.002,
+            look_speed: 2.5,
+            look_smoothing: 0.0,
+            max_speed: 3.0,
+            sprint_factor: 10.0,
+            acceleration: 30.0,
+            damping: 8.0,
+        }
+    }
 }
 
 #[derive(Bundle)]
@@ -40,70 +78,120 @@ impl CameraBundle {
                 transform,
                 ..default()
             },
-            controller: CameraController {},
+            controller: CameraController {
+                translate: true,
+                ..default()
+            },
         }
     }
 }
 
-#[derive(Component, Reflect, Debug)]
-struct CameraController {}
+#[derive(Component, Reflect, Debug, Default)]
+#[reflect(Component)]
+pub struct CameraController {
+    /// Whether control events move the camera, rather than only turning it.
+    pub translate: bool,
+    pub velocity: Vec3,
+    /// Pitch and yaw still to turn by while smoothing the look.
+    pub pending_rotation: Vec2,
+}
 
 #[derive(Event)]
 pub struct CameraControlEvent {
+    /// Pitch and yaw to turn by.
     pub delta_rotation: Vec2,
+    /// Wished direction of travel in the camera's frame, no longer than 1.
     pub delta_translation: Vec3,
+    pub sprint: bool,
+}
+
+/// Pitch and yaw the look actions ask for this frame.
+pub fn look_delta(settings: &CameraControlSettings, time: &Time, actions: &ActionState) -> Vec2 {
+    use Action::*;
+
+    // Mouse motion is already a distance, whereas look buttons and sticks
+    // turn at a rate.
+    Vec2::new(
+        actions.motion_axis(LookDown, LookUp),
+        actions.motion_axis(LookRight, LookLeft),
+    ) * settings.rotate_sensitivity
+        + Vec2::new(
+            actions.axis(LookDown, LookUp),
+            actions.axis(LookRight, LookLeft),
+        ) * settings.look_speed
+            * time.delta_seconds()
 }
 
 fn input(
     settings: Res<CameraControlSettings>,
-    keys: Res<Input<KeyCode>>,
-    mut mouse_events: EventReader<MouseMotion>,
+    time: Res<Time>,
+    actions: Res<ActionState>,
     mut control_events: EventWriter<CameraControlEvent>,
 ) {
-    let delta_rotation = (mouse_events.iter().fold(Vec2::ZERO, |z, ev| z - ev.delta)
-        * settings.rotate_sensitivity)
-        .yx();
-
-    let mut delta_translation = keys.get_pressed().fold(Vec3::ZERO, |z, keycode| {
-        z + match keycode {
-            KeyCode::W => -Vec3::Z,
-            KeyCode::A => -Vec3::X,
-            KeyCode::S => Vec3::Z,
-            KeyCode::D => Vec3::X,
-            KeyCode::ControlLeft => -Vec3::Y,
-            KeyCode::Space => Vec3::Y,
-            _ => Vec3::ZERO,
-        }
-    });
-    if delta_translation != Vec3::ZERO {
-        delta_translation = delta_translation.normalize()
-            * if keys.pressed(KeyCode::ShiftLeft) {
-                settings.move_speed * settings.sprint_factor
-            } else {
-                settings.move_speed
-            };
-    }
+    use Action::*;
+
+    let delta_rotation = look_delta(&settings, &time, &actions);
+    let sprint = actions.pressed(Sprint);
+    let delta_translation = Vec3::new(
+        actions.axis(MoveLeft, MoveRight),
+        actions.axis(MoveDown, MoveUp),
+        actions.axis(MoveForward, MoveBack),
+    )
+    .clamp_length_max(1.0);
 
     if delta_rotation != Vec2::ZERO || delta_translation != Vec3::ZERO {
         control_events.send(CameraControlEvent {
             delta_rotation,
             delta_translation,
+            sprint,
         })
     }
 }
 
+/// Turns cameras by the look input and moves them at a velocity that
+/// accelerates towards the wished one, so speed doesn't depend on the frame
+/// rate.
 fn control(
+    settings: Res<CameraControlSettings>,
+    time: Res<Time>,
     mut events: EventReader<CameraControlEvent>,
-    mut cameras: Query<(&CameraController, &mut Transform)>,
+    mut cameras: Query<(&mut CameraController, &mut Transform)>,
 ) {
+    let (mut rotation, mut direction, mut sprint) = (Vec2::ZERO, Vec3::ZERO, false);
     for ev in events.iter() {
-        cameras.for_each_mut(|(_, mut tr)| {
-            tr.rotate_y(ev.delta_rotation.y);
-            let max_drx = tr.forward().angle_between(Vec3::Y);
-            tr.rotate_local_x(ev.delta_rotation.x.clamp(max_drx - PI, max_drx));
-
-            let dtl = tr.rotation * ev.delta_translation;
-            tr.translation += dtl;
-        })
+        rotation += ev.delta_rotation;
+        direction += ev.delta_translation;
+        sprint |= ev.sprint;
     }
+    let direction = direction.clamp_length_max(1.0);
+    let speed = settings.max_speed * if sprint { settings.sprint_factor } else { 1.0 };
+
+    let dt = time.delta_seconds().min(MAX_DELTA_SECONDS);
+    cameras.for_each_mut(|(mut controller, mut tr)| {
+        controller.pending_rotation += rotation;
+        let turn = if settings.look_smoothing > 0.0 {
+            controller.pending_rotation * (1.0 - (-dt / settings.look_smoothing).exp())
+        } else {
+            controller.pending_rotation
+        };
+        controller.pending_rotation -= turn;
+
+        tr.rotate_y(turn.y);
+        let max_drx = tr.forward().angle_between(Vec3::Y);
+        tr.rotate_local_x(turn.x.clamp(max_drx - PI, max_drx));
+
+        if !controller.translate {
+            controller.velocity = Vec3::ZERO;
+            return;
+        }
+        if direction == Vec3::ZERO {
+            controller.velocity *= (-settings.damping * dt).exp();
+        } else {
+            let target = tr.rotation * direction * speed;
+            let change =
+                (target - controller.velocity).clamp_length_max(settings.acceleration * dt);
+            controller.velocity += change;
+        }
+        tr.translation += controller.velocity * dt;
+    })
 }
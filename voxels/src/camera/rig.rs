@@ -0,0 +1,494 @@
+use std::{fs, io, path::Path};
+
+use bevy::{prelude::*, transform::TransformSystem};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cave::{index::CaveChunkIndex, world::CaveWorld},
+    controls::{Action, ActionState},
+    player::Player,
+};
+
+use super::{look_delta, CameraControlSettings};
+
+/// Views other than the player's own: a camera that follows the player,
+/// orbits a point or plays back a path, taking over from the player's camera
+/// while it is in use.
+pub struct CameraRigPlugin;
+
+impl Plugin for CameraRigPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<CameraRigSettings>()
+            .register_type::<CameraRig>()
+            .register_type::<CameraMode>()
+            .insert_resource(CameraRigSettings::default())
+            .insert_resource(CameraPath::load(CAMERA_PATH))
+            .add_state::<CameraMode>()
+            .add_systems(Startup, spawn_rig)
+            .add_systems(
+                Update,
+                (cycle_mode, record_keyframes).run_if(resource_exists::<ActionState>()),
+            )
+            .add_systems(
+                OnEnter(CameraMode::Orbit),
+                (
+                    start_orbit,
+                    pick_orbit_center.run_if(resource_exists::<CaveChunkIndex>()),
+                )
+                    .chain()
+                    .after(start_transition),
+            )
+            .add_systems(OnEnter(CameraMode::Cinematic), start_path)
+            .add_systems(
+                PostUpdate,
+                (
+                    follow_player.run_if(
+                        in_state(CameraMode::FirstPerson)
+                            .or_else(in_state(CameraMode::ThirdPerson)),
+                    ),
+                    keep_out_of_walls
+                        .run_if(in_state(CameraMode::ThirdPerson))
+                        .run_if(resource_exists::<CaveChunkIndex>()),
+                    orbit
+                        .run_if(in_state(CameraMode::Orbit))
+                        .run_if(resource_exists::<ActionState>()),
+                    play_path.run_if(in_state(CameraMode::Cinematic)),
+                    place_rig,
+                )
+                    .chain()
+                    .before(TransformSystem::TransformPropagate),
+            );
+        for mode in CameraMode::ALL {
+            app.add_systems(OnEnter(mode), start_transition);
+        }
+    }
+}
+
+/// Path read at startup and written whenever a keyframe is recorded,
+/// relative to the working directory.
+pub const CAMERA_PATH: &str = "camera_path.ron";
+
+/// The player's camera, kept apart from the rig's.
+type PlayerView = (With<Player>, Without<CameraRig>);
+/// Either camera.
+type AnyView = Or<(With<Player>, With<CameraRig>)>;
+
+/// Closest the third person camera gets to a wall behind the player.
+const WALL_CLEARANCE: f32 = 0.2;
+/// Orbit pitch stops short of straight up or down, where yaw is undefined.
+const MAX_ORBIT_PITCH: f32 = 1.5;
+
+#[derive(States, Reflect, Default, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum CameraMode {
+    /// The player's own camera.
+    #[default]
+    FirstPerson,
+    /// Behind and above the player, turning with it.
+    ThirdPerson,
+    /// Around the point the player looked at when switching, turned by the
+    /// look actions and zoomed by moving forward and back.
+    Orbit,
+    /// Plays back the `CameraPath`.
+    Cinematic,
+}
+
+impl CameraMode {
+    pub const ALL: [CameraMode; 4] = [
+        CameraMode::FirstPerson,
+        CameraMode::ThirdPerson,
+        CameraMode::Orbit,
+        CameraMode::Cinematic,
+    ];
+}
+
+#[derive(Resource, Reflect, Debug)]
+#[reflect(Resource)]
+pub struct CameraRigSettings {
+    /// Seconds spent blending from the old view to the new one when the mode
+    /// changes.
+    pub transition_time: f32,
+    /// Third person camera position in the player's frame.
+    pub follow_offset: Vec3,
+    /// Furthest point picked as the orbit centre, falling back to
+    /// `orbit_distance` ahead when nothing is hit.
+    pub orbit_pick_distance: f32,
+    pub orbit_distance: f32,
+    pub orbit_min_distance: f32,
+    /// Exponential rate per second the orbit distance changes by when
+    /// zooming.
+    pub orbit_zoom_speed: f32,
+    /// Seconds after the previous keyframe a recorded keyframe plays at.
+    pub keyframe_interval: f32,
+    /// Whether the path starts over once it ends, rather than holding the
+    /// last keyframe.
+    pub loop_path: bool,
+}
+
+impl Default for CameraRigSettings {
+    fn default() -> Self {
+        Self {
+            transition_time: 0.5,
+            follow_offset: Vec3::new(0.0, 0.5, 4.0),
+            orbit_pick_distance: 64.0,
+            orbit_distance: 8.0,
+            orbit_min_distance: 1.0,
+            orbit_zoom_speed: 1.5,
+            keyframe_interval: 2.0,
+            loop_path: true,
+        }
+    }
+}
+
+/// State of the rig camera. It is only rendered from outside first person
+/// mode, and while blending back into it.
+#[derive(Component, Reflect, Debug, Default)]
+#[reflect(Component)]
+pub struct CameraRig {
+    /// View the current mode asks for.
+    pub target: Transform,
+    /// View shown when the mode changed, blended away from.
+    pub from: Transform,
+    /// Seconds since the mode changed.
+    pub elapsed: f32,
+    pub orbit_center: Vec3,
+    /// Orbit yaw and pitch.
+    pub orbit_angles: Vec2,
+    pub orbit_distance: f32,
+    /// Seconds into the camera path.
+    pub path_time: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    /// Seconds from the start of the path.
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+}
+
+/// Keyframes played back in cinematic mode, along a Catmull-Rom spline.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraPath {
+    /// In time order.
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraPath {
+    /// Reads a path from a RON file, empty if there is none or it can't be
+    /// read.
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|err| {
+                warn!(path = ?path, err = %err, "invalid camera path, starting empty");
+                Self::default()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => {
+                warn!(path = ?path, err = ?err, "failed to read camera path, starting empty");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let text = ron::ser::to_string_pretty(self, default())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(path, text)
+    }
+
+    /// Seconds from the start to the last keyframe.
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// View at `time`, holding the first and last keyframes outside the path.
+    pub fn sample(&self, time: f32) -> Option<Transform> {
+        let keyframes = &self.keyframes;
+        let last = keyframes.len().checked_sub(1)?;
+        let i = keyframes
+            .iter()
+            .rposition(|keyframe| keyframe.time <= time)
+            .unwrap_or(0);
+        let (k1, k2) = (keyframes[i], keyframes[(i + 1).min(last)]);
+        if i == last || time <= k1.time {
+            return Some(Transform::from_translation(k1.translation).with_rotation(k1.rotation));
+        }
+        let (k0, k3) = (keyframes[i.saturating_sub(1)], keyframes[(i + 2).min(last)]);
+        let t = (time - k1.time) / (k2.time - k1.time).max(f32::EPSILON);
+
+        let translation = catmull_rom(
+            [k0, k1, k2, k3].map(|keyframe| keyframe.translation.extend(0.0)),
+            t,
+        )
+        .truncate();
+        // Quaternions on the same hemisphere as the current one, so the
+        // spline takes the short way round.
+        let rotation = [k0, k1, k2, k3].map(|keyframe| {
+            let rotation = Vec4::from(keyframe.rotation);
+            if rotation.dot(Vec4::from(k1.rotation)) < 0.0 {
+                -rotation
+            } else {
+                rotation
+            }
+        });
+        let rotation = Quat::from_vec4(catmull_rom(rotation, t)).normalize();
+        Some(Transform::from_translation(translation).with_rotation(rotation))
+    }
+}
+
+fn catmull_rom([p0, p1, p2, p3]: [Vec4; 4], t: f32) -> Vec4 {
+    let (t2, t3) = (t * t, t * t * t);
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+fn spawn_rig(mut commands: Commands) {
+    commands.spawn((
+        Camera3dBundle {
+            camera: Camera {
+                is_active: false,
+                ..default()
+            },
+            ..default()
+        },
+        CameraRig::default(),
+        Name::new("Camera rig"),
+    ));
+}
+
+fn cycle_mode(
+    actions: Res<ActionState>,
+    path: Res<CameraPath>,
+    mode: Res<State<CameraMode>>,
+    mut next_mode: ResMut<NextState<CameraMode>>,
+) {
+    if !actions.just_pressed(Action::CycleCamera) {
+        return;
+    }
+    next_mode.set(match mode.get() {
+        CameraMode::FirstPerson => CameraMode::ThirdPerson,
+        CameraMode::ThirdPerson => CameraMode::Orbit,
+        // Nothing to play back without keyframes.
+        CameraMode::Orbit if path.keyframes.is_empty() => CameraMode::FirstPerson,
+        CameraMode::Orbit => CameraMode::Cinematic,
+        CameraMode::Cinematic => CameraMode::FirstPerson,
+    });
+}
+
+fn record_keyframes(
+    actions: Res<ActionState>,
+    settings: Res<CameraRigSettings>,
+    mut path: ResMut<CameraPath>,
+    views: Query<(&Camera, &Transform), AnyView>,
+) {
+    if actions.just_pressed(Action::ClearKeyframes) {
+        path.keyframes.clear();
+    }
+    if !actions.just_pressed(Action::RecordKeyframe) {
+        return;
+    }
+    let view = if let Some((_, view)) = views.iter().find(|(camera, _)| camera.is_active) {
+        view
+    } else {
+        return;
+    };
+
+    let time = path
+        .keyframes
+        .last()
+        .map_or(0.0, |keyframe| keyframe.time + settings.keyframe_interval);
+    path.keyframes.push(CameraKeyframe {
+        time,
+        translation: view.translation,
+        rotation: view.rotation,
+    });
+    if let Err(err) = path.save(CAMERA_PATH) {
+        warn!(path = CAMERA_PATH, err = ?err, "failed to save camera path");
+    }
+}
+
+/// Starts blending from whichever camera is showing.
+fn start_transition(
+    mode: Res<State<CameraMode>>,
+    players: Query<(&Camera, &Transform), PlayerView>,
+    mut rigs: Query<(&mut CameraRig, &Camera, &Transform)>,
+) {
+    for (mut rig, camera, transform) in &mut rigs {
+        let player = players.iter().find(|(camera, _)| camera.is_active);
+        rig.from = match (camera.is_active, player) {
+            (true, _) => *transform,
+            (false, Some((_, player))) => *player,
+            (false, None) => *transform,
+        };
+        // Already showing the player's view, so there is nothing to blend.
+        rig.elapsed = if *mode.get() == CameraMode::FirstPerson && !camera.is_active {
+            f32::INFINITY
+        } else {
+            0.0
+        };
+    }
+}
+
+/// Orbits around the point ahead of the current view, from where the view
+/// is.
+fn start_orbit(settings: Res<CameraRigSettings>, mut rigs: Query<&mut CameraRig>) {
+    for mut rig in &mut rigs {
+        let from = rig.from;
+        let center = from.translation + from.forward() * settings.orbit_distance;
+        orbit_from(&mut rig, center, from.translation);
+    }
+}
+
+fn pick_orbit_center(
+    settings: Res<CameraRigSettings>,
+    cave_world: CaveWorld,
+    mut rigs: Query<&mut CameraRig>,
+) {
+    for mut rig in &mut rigs {
+        let from = rig.from;
+        if let Some(hit) = cave_world.raycast(
+            from.translation,
+            from.forward(),
+            settings.orbit_pick_distance,
+        ) {
+            orbit_from(&mut rig, hit.position, from.translation);
+        }
+    }
+}
+
+fn orbit_from(rig: &mut CameraRig, center: Vec3, eye: Vec3) {
+    let offset = eye - center;
+    rig.orbit_center = center;
+    rig.orbit_distance = offset.length();
+    rig.orbit_angles = Vec2::new(
+        offset.x.atan2(offset.z),
+        -(offset.y / rig.orbit_distance.max(f32::EPSILON))
+            .clamp(-1.0, 1.0)
+            .asin(),
+    );
+    orbit_target(rig);
+}
+
+fn orbit_target(rig: &mut CameraRig) {
+    let rotation = Quat::from_euler(EulerRot::YXZ, rig.orbit_angles.x, rig.orbit_angles.y, 0.0);
+    rig.target =
+        Transform::from_translation(rig.orbit_center + rotation * Vec3::Z * rig.orbit_distance)
+            .with_rotation(rotation);
+}
+
+fn start_path(mut rigs: Query<&mut CameraRig>) {
+    for mut rig in &mut rigs {
+        rig.path_time = 0.0;
+    }
+}
+
+fn follow_player(
+    mode: Res<State<CameraMode>>,
+    settings: Res<CameraRigSettings>,
+    players: Query<&Transform, PlayerView>,
+    mut rigs: Query<&mut CameraRig>,
+) {
+    let player = if let Ok(player) = players.get_single() {
+        *player
+    } else {
+        return;
+    };
+    for mut rig in &mut rigs {
+        rig.target = if *mode.get() == CameraMode::ThirdPerson {
+            player.with_translation(player.transform_point(settings.follow_offset))
+        } else {
+            player
+        };
+    }
+}
+
+/// Pulls the third person camera in front of any voxel between it and the
+/// player.
+fn keep_out_of_walls(
+    cave_world: CaveWorld,
+    players: Query<&Transform, PlayerView>,
+    mut rigs: Query<&mut CameraRig>,
+) {
+    let player = if let Ok(player) = players.get_single() {
+        player.translation
+    } else {
+        return;
+    };
+    for mut rig in &mut rigs {
+        let offset = rig.target.translation - player;
+        if let Some(hit) = cave_world.raycast(player, offset, offset.length()) {
+            rig.target.translation =
+                player + offset.normalize() * (hit.distance - WALL_CLEARANCE).max(0.0);
+        }
+    }
+}
+
+fn orbit(
+    settings: Res<CameraRigSettings>,
+    control_settings: Res<CameraControlSettings>,
+    time: Res<Time>,
+    actions: Res<ActionState>,
+    mut rigs: Query<&mut CameraRig>,
+) {
+    let look = look_delta(&control_settings, &time, &actions);
+    let zoom = actions.axis(Action::MoveForward, Action::MoveBack);
+    for mut rig in &mut rigs {
+        let angles = rig.orbit_angles + Vec2::new(look.y, look.x);
+        rig.orbit_angles = Vec2::new(angles.x, angles.y.clamp(-MAX_ORBIT_PITCH, MAX_ORBIT_PITCH));
+        rig.orbit_distance = (rig.orbit_distance
+            * (zoom * settings.orbit_zoom_speed * time.delta_seconds()).exp())
+        .max(settings.orbit_min_distance);
+        orbit_target(&mut rig);
+    }
+}
+
+fn play_path(
+    settings: Res<CameraRigSettings>,
+    time: Res<Time>,
+    path: Res<CameraPath>,
+    mut rigs: Query<&mut CameraRig>,
+) {
+    let duration = path.duration();
+    for mut rig in &mut rigs {
+        rig.path_time += time.delta_seconds();
+        if settings.loop_path && duration > 0.0 {
+            rig.path_time %= duration;
+        }
+        if let Some(view) = path.sample(rig.path_time) {
+            rig.target = view;
+        }
+    }
+}
+
+/// Moves the rig along the blend into the mode's view and hands over between
+/// it and the player's camera.
+fn place_rig(
+    mode: Res<State<CameraMode>>,
+    settings: Res<CameraRigSettings>,
+    time: Res<Time>,
+    mut players: Query<&mut Camera, PlayerView>,
+    mut rigs: Query<(&mut CameraRig, &mut Camera, &mut Transform)>,
+) {
+    for (mut rig, mut camera, mut transform) in &mut rigs {
+        rig.elapsed += time.delta_seconds();
+        let t = (rig.elapsed / settings.transition_time.max(f32::EPSILON)).min(1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        *transform =
+            Transform::from_translation(rig.from.translation.lerp(rig.target.translation, t))
+                .with_rotation(rig.from.rotation.slerp(rig.target.rotation, t));
+
+        let active = *mode.get() != CameraMode::FirstPerson || t < 1.0;
+        if camera.is_active != active {
+            camera.is_active = active;
+        }
+        for mut player in &mut players {
+            if player.is_active == active {
+                player.is_active = !active;
+            }
+        }
+    }
+}
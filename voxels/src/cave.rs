@@ -1,16 +1,29 @@
-use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
+use bevy::{ecs::system::SystemParam, prelude::*};
 
-use crate::camera::CameraControlEvent;
 use crate::player::Player;
 
-use self::chunk::CaveChunkSettings;
-use self::spawn::SpawnedCaveChunks;
-
-mod chunk;
-mod mesh;
-mod pbr;
-mod spawn;
-mod voxelize;
+use self::chunk::{CaveChunk, CaveChunkSettings, CaveMeshMode};
+use self::generator::CaveGenerators;
+use self::region::CaveRegionStore;
+use self::schedule::{CaveChunkFailed, CaveChunkJob, CaveChunkScheduler, CaveChunkStage};
+use self::stream::{CaveStreamSettings, StreamedCaveChunks};
+
+pub mod chunk;
+pub mod diagnostics;
+pub mod edit;
+pub mod error;
+pub mod generator;
+pub mod index;
+pub mod material;
+pub mod mesh;
+pub mod pbr;
+pub mod region;
+pub mod schedule;
+pub mod stream;
+mod surface_nets;
+pub mod voxelize;
+pub mod world;
+pub mod worm;
 
 pub struct CavePlugin;
 
@@ -18,93 +31,211 @@ impl Plugin for CavePlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
             chunk::CaveChunkPlugin,
-            spawn::CaveSpawnPlugin,
-            voxelize::VoxelizeCaveChunkPlugin,
-            mesh::MeshCaveChunkPlugin,
+            diagnostics::CaveDiagnosticsPlugin,
+            edit::CaveEditPlugin,
+            generator::CaveGeneratorPlugin,
+            index::CaveIndexPlugin,
+            material::CaveMaterialPlugin,
+            region::CaveRegionPlugin,
+            schedule::CaveSchedulePlugin,
+            stream::CaveStreamPlugin,
         ))
-        .add_systems(Update, (pbr::insert_cave_chunk_pbr, spawn_around_player))
-        .add_systems(Startup, test_spawn.after(chunk::insert_settings));
+        .add_systems(
+            Update,
+            (
+                pbr::insert_cave_chunk_pbr,
+                schedule::handle_cave_chunk_jobs,
+                warn_failed_chunks,
+                apply_cave_chunk_settings,
+                spawn_around_player,
+                schedule::start_cave_chunk_jobs,
+                index::index_cave_chunks,
+            )
+                .chain(),
+        );
     }
 }
 
-fn test_spawn(
-    spawned_cave_chunks: Res<SpawnedCaveChunks>,
+/// Failed chunks stay `CaveChunkStage::Failed` while the scheduler backs off
+/// and tries them again.
+fn warn_failed_chunks(mut events: EventReader<CaveChunkFailed>) {
+    for ev in events.iter() {
+        warn!(entity = ?ev.entity, stage = ?ev.stage, error = %ev.error, "cave chunk failed");
+    }
+}
+
+/// Regenerates every chunk when settings that shape the cave change, and
+/// only remeshes them when the mesh mode does.
+fn apply_cave_chunk_settings(
+    mut scheduler: ResMut<CaveChunkScheduler>,
+    mut streamed_cave_chunks: ResMut<StreamedCaveChunks>,
     settings: Res<CaveChunkSettings>,
+    generators: Res<CaveGenerators>,
     mut commands: Commands,
+    mut cave_chunks: Query<&mut CaveChunk>,
+    mut applied: Local<Option<(u64, CaveMeshMode)>>,
 ) {
-    let task_pool = AsyncComputeTaskPool::get();
-
-    let max_lod = 10;
-    // let chunk_size: f32 = 0.64;
-    // let subdivisions = 5;
-    // let chunk_count = 16;
-    let chunk_size: f32 = 1.28;
-    let subdivisions = 5;
-    let chunk_count = 8;
-
-    let range = -chunk_count / 2..chunk_count / 2;
-    let max_y = chunk_size * 2_i32.pow(max_lod) as f32;
-    let min_y = -max_y;
-
-    let inner_range = -chunk_count / 4..chunk_count / 4;
-    for lod in 0..=max_lod {
-        for x in range.clone() {
-            for y in range.clone() {
-                for z in range.clone() {
-                    if lod != 0
-                        && inner_range.contains(&x)
-                        && inner_range.contains(&y)
-                        && inner_range.contains(&z)
-                    {
-                        continue;
-                    }
-
-                    let size = chunk_size * 2_i32.pow(lod) as f32;
-                    let origin = Vec3::new(x as f32, y as f32, z as f32) * size;
-                    if origin.y < min_y || origin.y + size > max_y {
-                        continue;
-                    }
-
-                    if spawned_cave_chunks.processing.len() >= task_pool.thread_num() {
-                        return;
-                    }
-
-                    commands.spawn_empty().insert(spawn::spawn_cave_chunk_task(
-                        task_pool,
-                        chunk::CaveChunkSettings {
-                            size,
-                            threshold: 0.04,
-                            frequency: 0.15,
-                            material: settings.material.clone(),
-                        },
-                        origin,
-                        subdivisions,
-                    ));
-                }
-            }
+    if !settings.is_changed() {
+        return;
+    }
+    let generator = if let Some(generator) = generators.get(&settings.generator) {
+        generator
+    } else {
+        return;
+    };
+    let streamed = &mut *streamed_cave_chunks;
+
+    let fingerprint = region::fingerprint(&settings, &*generator);
+    let previous = applied.replace((fingerprint, settings.mesh_mode));
+    if previous.map(|(world, _)| world) != Some(fingerprint) {
+        for (entity, _) in streamed.loaded.values() {
+            scheduler.cancel(*entity);
+            commands.entity(*entity).despawn_recursive();
         }
+        for entity in streamed.replaced.values() {
+            commands.entity(*entity).despawn_recursive();
+        }
+        streamed.loaded.clear();
+        streamed.replaced.clear();
+        return;
     }
+    if previous.map(|(_, mesh_mode)| mesh_mode) == Some(settings.mesh_mode) {
+        return;
+    }
+
+    streamed.loaded.retain(|_, (entity, _)| {
+        if let Ok(mut cave_chunk) = cave_chunks.get_mut(*entity) {
+            cave_chunk.settings.mesh_mode = settings.mesh_mode;
+            scheduler.remesh(*entity);
+            return true;
+        }
+        // Not generated yet, so spawned again with the new settings.
+        scheduler.cancel(*entity);
+        commands.entity(*entity).despawn_recursive();
+        false
+    });
+}
+
+/// Where the samples of new chunks come from.
+#[derive(SystemParam)]
+struct CaveChunkSources<'w> {
+    settings: Res<'w, CaveChunkSettings>,
+    store: Res<'w, CaveRegionStore>,
+    generators: Res<'w, CaveGenerators>,
 }
 
 fn spawn_around_player(
-    mut events: EventReader<CameraControlEvent>,
-    player: Query<&GlobalTransform, (With<Player>, Changed<GlobalTransform>)>,
+    mut scheduler: ResMut<CaveChunkScheduler>,
+    mut streamed_cave_chunks: ResMut<StreamedCaveChunks>,
+    stream_settings: Res<CaveStreamSettings>,
+    sources: CaveChunkSources,
+    mut commands: Commands,
+    player: Query<&GlobalTransform, With<Player>>,
+    mut cave_chunks: Query<(&CaveChunkStage, Option<&mut CaveChunk>)>,
 ) {
     let player = if let Ok(player) = player.get_single() {
         player
     } else {
         return;
     };
-
-    let moved = events.iter().any(|ev| ev.delta_translation != Vec3::ZERO);
-    if !moved {
+    let CaveChunkSources {
+        settings,
+        store,
+        generators,
+    } = sources;
+    let generator = if let Some(generator) = generators.get(&settings.generator) {
+        generator
+    } else {
+        if settings.is_changed() {
+            warn!(generator = settings.generator, "unknown cave generator");
+        }
         return;
+    };
+    let translation = player.translation();
+    let streamed = &mut *streamed_cave_chunks;
+
+    let center = (translation / stream_settings.chunk_size)
+        .floor()
+        .as_ivec3();
+    if streamed.center != Some(center) || stream_settings.is_changed() {
+        streamed.center = Some(center);
+        streamed.wanted = stream::chunks_around(&stream_settings, translation);
+
+        let wanted = &streamed.wanted;
+        streamed.loaded.retain(|key, (entity, detail)| {
+            match wanted.get(key) {
+                Some(wanted) if wanted == detail => return true,
+                // Seams only change how the samples are meshed.
+                Some(wanted) if wanted.subdivisions == detail.subdivisions => {
+                    if let Ok((_, Some(mut cave_chunk))) = cave_chunks.get_mut(*entity) {
+                        cave_chunk.seams = wanted.seams;
+                        scheduler.remesh(*entity);
+                        *detail = *wanted;
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+            scheduler.cancel(*entity);
+            // Generated chunks stay shown until their replacement is, rather
+            // than leave a hole.
+            let shown = cave_chunks
+                .get(*entity)
+                .is_ok_and(|(_, cave_chunk)| cave_chunk.is_some());
+            if shown && wanted.contains_key(key) && !streamed.replaced.contains_key(key) {
+                streamed.replaced.insert(*key, *entity);
+            } else {
+                commands.entity(*entity).despawn_recursive();
+            }
+            false
+        });
+
+        info!(
+            translation = ?translation,
+            wanted = streamed.wanted.len(),
+            loaded = streamed.loaded.len()
+        );
     }
 
-    let _task_pool = AsyncComputeTaskPool::get();
-    info!(translation = ?player.translation());
-
-    // let subdivisions = (settings.max_subdivisions as f32
-    //     - (origin.length() / 10.0).log2().max(0.0))
-    // .max(1.0) as u32;
+    // Despawned in the frame the replacement's meshes are added.
+    streamed.replaced.retain(|key, entity| {
+        let ready = streamed.loaded.get(key).is_some_and(|(loaded, _)| {
+            cave_chunks
+                .get(*loaded)
+                .is_ok_and(|(stage, _)| *stage == CaveChunkStage::Ready)
+        });
+        if ready || !streamed.wanted.contains_key(key) {
+            commands.entity(*entity).despawn_recursive();
+            return false;
+        }
+        true
+    });
+
+    let missing: Vec<_> = streamed
+        .wanted
+        .iter()
+        .filter(|(key, _)| !streamed.loaded.contains_key(*key))
+        .map(|(key, detail)| (*key, *detail))
+        .collect();
+
+    // The scheduler decides what gets worked on first.
+    for (key, detail) in missing {
+        let entity = commands.spawn((key, CaveChunkStage::Generating)).id();
+        scheduler.queue(
+            entity,
+            CaveChunkJob::Generate {
+                generator: generator.clone(),
+                store: store.clone(),
+                settings: Box::new(CaveChunkSettings {
+                    size: stream_settings.lod_size(key.lod),
+                    ..settings.clone()
+                }),
+                key,
+                origin: key.origin(&stream_settings),
+                subdivisions: detail.subdivisions,
+                seams: detail.seams,
+            },
+        );
+        streamed.loaded.insert(key, (entity, detail));
+    }
 }
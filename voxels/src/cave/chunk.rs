@@ -1,25 +1,121 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    borrow::Cow,
+    sync::{Arc, RwLock},
+};
 
 use bevy::prelude::*;
 
+use super::{
+    generator::{CaveGenerator, CaveSampleGrid, NOISE_GENERATOR},
+    material::{CaveMaterial, CaveTextures},
+    worm::{carve_worms, worms_near, CaveWorm, CaveWormSettings},
+};
+
 pub struct CaveChunkPlugin;
 
 impl Plugin for CaveChunkPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, insert_settings);
+        app.register_type::<CaveChunkSettings>()
+            .register_type::<CaveNoiseKind>()
+            .register_type::<CavePaletteEntry>()
+            .register_type::<CaveStratum>()
+            .register_type::<CaveStratumMaterial>()
+            .register_type::<CaveMeshMode>()
+            .register_type::<CaveWormSettings>()
+            .add_systems(Startup, insert_settings);
     }
 }
 
+/// Names, weights and materials of the built in palette.
+pub fn default_palette() -> Vec<(&'static str, f32, CaveMaterial)> {
+    vec![
+        (
+            "rock",
+            6.0,
+            CaveMaterial::new(Color::hex("ffd891").unwrap(), 0.5, 0.5),
+        ),
+        (
+            "mud",
+            2.0,
+            CaveMaterial::new(Color::hex("6b4f3a").unwrap(), 0.0, 0.9),
+        ),
+        (
+            "ore",
+            1.0,
+            CaveMaterial::new(Color::hex("8a8f99").unwrap(), 0.9, 0.3),
+        ),
+        (
+            "crystal",
+            1.0,
+            CaveMaterial::new(Color::hex("7fe0ff").unwrap(), 0.1, 0.05),
+        ),
+        // Only found in deeper strata.
+        (
+            "basalt",
+            0.0,
+            CaveMaterial::new(Color::hex("34343a").unwrap(), 0.1, 0.8),
+        ),
+        (
+            "magma",
+            0.0,
+            CaveMaterial::new(Color::hex("ff5a1f").unwrap(), 0.0, 0.6),
+        ),
+    ]
+}
+
+/// Layers below the surface one described by `CaveChunkSettings` itself.
+pub fn default_strata() -> Vec<CaveStratum> {
+    let materials = |weights: &[(&str, f32)]| {
+        weights
+            .iter()
+            .map(|(name, weight)| CaveStratumMaterial {
+                name: (*name).into(),
+                weight: *weight,
+            })
+            .collect()
+    };
+    vec![
+        CaveStratum {
+            name: "crystal caverns".into(),
+            depth: 16.0,
+            threshold: 0.09,
+            frequency: 0.06,
+            noise: CaveNoiseKind::Fbm,
+            materials: materials(&[("rock", 2.0), ("crystal", 5.0), ("ore", 1.0)]),
+        },
+        CaveStratum {
+            name: "lava tubes".into(),
+            depth: 48.0,
+            threshold: -0.02,
+            frequency: 0.25,
+            noise: CaveNoiseKind::Fbm,
+            materials: materials(&[("basalt", 6.0), ("magma", 2.0), ("ore", 1.0)]),
+        },
+    ]
+}
+
 pub fn insert_settings(world: &mut World) {
-    let mut materials = world
-        .get_resource_mut::<Assets<StandardMaterial>>()
-        .unwrap();
-    let material = materials.add(StandardMaterial {
-        base_color: Color::hex("ffd891").unwrap(),
-        metallic: 0.5,
-        perceptual_roughness: 0.5,
-        ..Default::default()
-    });
+    let palette = default_palette();
+    let textures = CaveTextures::generate(
+        &mut world.resource_mut::<Assets<Image>>(),
+        palette.len() as u32,
+    );
+    let mut materials = world.resource_mut::<Assets<CaveMaterial>>();
+    let palette = palette
+        .into_iter()
+        .enumerate()
+        .map(|(layer, (name, weight, material))| CavePaletteEntry {
+            name: name.into(),
+            weight,
+            material: materials.add(CaveMaterial {
+                layer: layer as u32,
+                albedo: textures.albedo.clone(),
+                normal: textures.normal.clone(),
+                roughness: textures.roughness.clone(),
+                ..material
+            }),
+        })
+        .collect();
 
     // let edge_material = materials.add(StandardMaterial {
     //     base_color: Color::hex("ffff22").unwrap(),
@@ -28,21 +124,206 @@ pub fn insert_settings(world: &mut World) {
     //     ..Default::default()
     // });
 
-    let settings = CaveChunkSettings {
-        size: 0.64,
-        threshold: 0.04,
-        frequency: 0.15,
-        material,
-    };
+    // Settings inserted before startup, such as by tests, keep everything but
+    // the palette if they don't bring their own.
+    let mut settings = world
+        .remove_resource::<CaveChunkSettings>()
+        .unwrap_or_default();
+    if settings.palette.is_empty() {
+        settings.palette = palette;
+    }
     world.insert_resource(settings);
 }
 
-#[derive(Resource, Debug, Clone)]
+#[derive(Resource, Reflect, Debug, Clone)]
+#[reflect(Resource)]
 pub struct CaveChunkSettings {
     pub size: f32,
     pub threshold: f32,
     pub frequency: f32,
-    pub material: Handle<StandardMaterial>,
+    pub seed: i32,
+    pub noise: CaveNoiseKind,
+    /// Ignored by `CaveNoiseKind::Cellular`, as are `lacunarity` and `gain`.
+    pub octaves: u8,
+    pub lacunarity: f32,
+    pub gain: f32,
+    pub material_frequency: f32,
+    /// Materials, weighted for the surface stratum.
+    pub palette: Vec<CavePaletteEntry>,
+    pub mesh_mode: CaveMeshMode,
+    /// Name of the `CaveGenerators` entry that fills chunks.
+    pub generator: String,
+    /// Strata below the surface one, by increasing depth.
+    pub strata: Vec<CaveStratum>,
+    /// Depth over which neighbouring strata blend.
+    pub stratum_blend: f32,
+    pub biome_frequency: f32,
+    /// How far biome noise moves strata boundaries up or down.
+    pub biome_depth_shift: f32,
+    /// Tunnels carved through whatever the generator filled in.
+    pub worms: CaveWormSettings,
+    /// Distance from the threshold every sample of the coarse pre-pass has
+    /// to keep for a chunk to be taken as uniformly empty or solid without
+    /// sampling it fully. Larger values miss fewer small pockets.
+    pub uniform_margin: f32,
+}
+
+impl Default for CaveChunkSettings {
+    fn default() -> Self {
+        Self {
+            size: 0.64,
+            threshold: 0.04,
+            frequency: 0.15,
+            seed: 42,
+            noise: CaveNoiseKind::Fbm,
+            octaves: 3,
+            lacunarity: 0.5,
+            gain: 2.0,
+            material_frequency: 0.05,
+            palette: Vec::new(),
+            mesh_mode: CaveMeshMode::Blocky,
+            generator: NOISE_GENERATOR.into(),
+            strata: default_strata(),
+            stratum_blend: 8.0,
+            biome_frequency: 0.01,
+            biome_depth_shift: 8.0,
+            worms: CaveWormSettings::default(),
+            uniform_margin: 0.05,
+        }
+    }
+}
+
+impl CaveChunkSettings {
+    /// Palette id for a material noise sample, 0 being empty. Entries take a
+    /// share of the noise range proportional to their weight.
+    pub fn palette_id(&self, material_sample: f32) -> u8 {
+        let weights: Vec<_> = self.palette.iter().map(|entry| entry.weight).collect();
+        Self::weighted_palette_id(&weights, material_sample)
+    }
+
+    /// Like `palette_id`, with a weight for every palette entry.
+    pub fn weighted_palette_id(weights: &[f32], material_sample: f32) -> u8 {
+        let total: f32 = weights.iter().sum();
+        let mut remaining = (material_sample * 0.5 + 0.5).clamp(0.0, 1.0) * total;
+        for (i, weight) in weights.iter().enumerate() {
+            remaining -= weight;
+            if remaining <= 0.0 {
+                return i as u8 + 1;
+            }
+        }
+        weights.len() as u8
+    }
+
+    /// The surface stratum followed by `strata`.
+    pub fn all_strata(&self) -> Vec<CaveStratum> {
+        let surface = CaveStratum {
+            name: "surface".into(),
+            depth: f32::NEG_INFINITY,
+            threshold: self.threshold,
+            frequency: self.frequency,
+            noise: self.noise,
+            materials: self
+                .palette
+                .iter()
+                .map(|entry| CaveStratumMaterial {
+                    name: entry.name.clone(),
+                    weight: entry.weight,
+                })
+                .collect(),
+        };
+        std::iter::once(surface)
+            .chain(self.strata.iter().cloned())
+            .collect()
+    }
+
+    /// Weight of every stratum of `all_strata` at a depth, blending across
+    /// each boundary over `stratum_blend`.
+    pub fn strata_weights(&self, strata: &[CaveStratum], depth: f32, weights: &mut [f32]) {
+        let below = |stratum: &CaveStratum| {
+            let t = ((depth - stratum.depth) / self.stratum_blend.max(f32::EPSILON) + 0.5)
+                .clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        };
+        for (i, stratum) in strata.iter().enumerate() {
+            let next = strata.get(i + 1).map_or(0.0, below);
+            weights[i] = below(stratum) * (1.0 - next);
+        }
+        let total: f32 = weights.iter().sum();
+        if total > 0.0 {
+            weights.iter_mut().for_each(|weight| *weight /= total);
+        }
+    }
+
+    pub fn palette_entry(&self, id: u8) -> Option<&CavePaletteEntry> {
+        self.palette.get(id.checked_sub(1)? as usize)
+    }
+
+    pub fn palette_id_by_name(&self, name: &str) -> Option<u8> {
+        self.palette
+            .iter()
+            .position(|entry| entry.name == name)
+            .map(|i| i as u8 + 1)
+    }
+}
+
+#[derive(Reflect, Debug, Clone, Default)]
+pub struct CavePaletteEntry {
+    pub name: String,
+    pub weight: f32,
+    pub material: Handle<CaveMaterial>,
+}
+
+/// A depth band of the cave with its own shape and materials.
+#[derive(Reflect, Debug, Clone, Default)]
+pub struct CaveStratum {
+    pub name: String,
+    /// Depth below y = 0 where the stratum takes over from the one above.
+    pub depth: f32,
+    pub threshold: f32,
+    pub frequency: f32,
+    pub noise: CaveNoiseKind,
+    /// Weights of `CaveChunkSettings::palette` entries by name, missing ones
+    /// being zero.
+    pub materials: Vec<CaveStratumMaterial>,
+}
+
+impl CaveStratum {
+    pub fn palette_weights(&self, palette: &[CavePaletteEntry]) -> Vec<f32> {
+        palette
+            .iter()
+            .map(|entry| {
+                self.materials
+                    .iter()
+                    .filter(|material| material.name == entry.name)
+                    .map(|material| material.weight)
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+#[derive(Reflect, Debug, Clone, Default)]
+pub struct CaveStratumMaterial {
+    pub name: String,
+    pub weight: f32,
+}
+
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaveNoiseKind {
+    #[default]
+    Fbm,
+    Ridge,
+    Turbulence,
+    Cellular,
+}
+
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaveMeshMode {
+    /// Greedy quads over the voxel grid.
+    #[default]
+    Blocky,
+    /// Surface nets over the density field.
+    Smooth,
 }
 
 #[derive(Bundle)]
@@ -52,55 +333,273 @@ pub struct CaveChunkBundle {
 }
 
 impl CaveChunkBundle {
-    pub fn new(settings: &CaveChunkSettings, transform: Transform, subdivisions: u32) -> Self {
+    pub fn new(cave_chunk: CaveChunk, transform: Transform) -> Self {
         CaveChunkBundle {
             spatial: SpatialBundle {
                 transform,
                 ..default()
             },
-            cave_chunk: CaveChunk::new(settings, transform.translation, subdivisions),
+            cave_chunk,
         }
     }
 }
 
+/// Samples per axis of the pre-pass that spots uniform chunks, as a power of
+/// two.
+const PREPASS_SUBDIVISIONS: u32 = 2;
+
+/// Distance from the threshold uniform chunks' samples are taken to be at
+/// when they are needed one by one.
+pub const UNIFORM_DENSITY: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaveChunkFill {
+    Empty,
+    /// Solid throughout, of one palette id.
+    Solid(u8),
+    Mixed,
+}
+
+/// Density and palette id of every sample, or only what they all share for
+/// chunks that are uniformly empty or solid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaveChunkSamples {
+    Empty,
+    /// Solid throughout, of one palette id. Chunks the pre-pass took as
+    /// solid only know the id most of its samples had.
+    Solid(u8),
+    Mixed {
+        density: Vec<f32>,
+        /// Palette id of every sample, solid or not.
+        materials: Vec<u8>,
+    },
+}
+
+impl CaveChunkSamples {
+    /// Keeps the samples only if they are on both sides of the threshold,
+    /// or solid of more than one material.
+    pub fn new(settings: &CaveChunkSettings, density: Vec<f32>, materials: Vec<u8>) -> Self {
+        if density.iter().all(|d| *d <= settings.threshold) {
+            Self::Empty
+        } else if density.iter().all(|d| *d > settings.threshold)
+            && materials.iter().all(|m| *m == materials[0])
+        {
+            Self::Solid(materials[0])
+        } else {
+            Self::Mixed { density, materials }
+        }
+    }
+
+    pub fn fill(&self) -> CaveChunkFill {
+        match self {
+            Self::Empty => CaveChunkFill::Empty,
+            Self::Solid(material) => CaveChunkFill::Solid(*material),
+            Self::Mixed { .. } => CaveChunkFill::Mixed,
+        }
+    }
+
+    /// Bytes held for the samples.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            Self::Mixed { density, materials } => {
+                density.capacity() * std::mem::size_of::<f32>() + materials.capacity()
+            }
+            _ => 0,
+        }
+    }
+
+    /// All `len` densities and palette ids, made up for uniform chunks.
+    pub fn expanded(
+        &self,
+        settings: &CaveChunkSettings,
+        len: usize,
+    ) -> (Cow<'_, [f32]>, Cow<'_, [u8]>) {
+        match self {
+            Self::Empty => (
+                vec![settings.threshold - UNIFORM_DENSITY; len].into(),
+                vec![0; len].into(),
+            ),
+            Self::Solid(material) => (
+                vec![settings.threshold + UNIFORM_DENSITY; len].into(),
+                vec![*material; len].into(),
+            ),
+            Self::Mixed { density, materials } => (density.into(), materials.into()),
+        }
+    }
+
+    /// Turns uniform samples into mixed ones of `len` samples so they can be
+    /// changed one by one.
+    pub fn expand(&mut self, settings: &CaveChunkSettings, len: usize) -> (&mut [f32], &mut [u8]) {
+        if !matches!(self, Self::Mixed { .. }) {
+            let (density, materials) = self.expanded(settings, len);
+            *self = Self::Mixed {
+                density: density.into_owned(),
+                materials: materials.into_owned(),
+            };
+        }
+        match self {
+            Self::Mixed { density, materials } => (density, materials),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn sample_grid(settings: &CaveChunkSettings, origin: Vec3, subdivisions: u32) -> CaveSampleGrid {
+    let sample_count = 2_usize.pow(subdivisions);
+    CaveSampleGrid {
+        origin,
+        voxel_size: settings.size / sample_count as f32,
+        length: sample_count + 2,
+    }
+}
+
+/// Largest difference between neighbouring samples of a grid `length` per
+/// axis.
+fn largest_step(density: &[f32], length: usize) -> f32 {
+    let mut step = 0.0_f32;
+    for (i, d) in density.iter().enumerate() {
+        let (x, y, z) = (i % length, i / length % length, i / length / length);
+        for (next, stride) in [(x, 1), (y, length), (z, length * length)] {
+            if next + 1 < length {
+                step = step.max((density[i + stride] - d).abs());
+            }
+        }
+    }
+    step
+}
+
+fn most_common(materials: &[u8]) -> u8 {
+    let mut counts = [0_usize; 256];
+    for material in materials {
+        counts[*material as usize] += 1;
+    }
+    (0..=u8::MAX)
+        .max_by_key(|material| counts[*material as usize])
+        .unwrap_or_default()
+}
+
 #[derive(Component, Debug, Clone)]
 pub struct CaveChunk {
     pub subdivisions: u32,
-    pub noise_samples: Arc<RwLock<Vec<f32>>>,
+    /// Faces bordering a chunk of a different resolution, bit `axis * 2` for
+    /// the negative and `axis * 2 + 1` for the positive side.
+    pub seams: u8,
+    /// `2^subdivisions + 2` samples per axis, starting one voxel before the
+    /// chunk origin so the neighbouring space around the chunk is known.
+    pub samples: Arc<RwLock<CaveChunkSamples>>,
     pub settings: CaveChunkSettings,
 }
 
-#[derive(Component, Debug)]
-pub struct CaveChunkOriginTask {
-    pub task_entity: Entity,
-}
-
 impl CaveChunk {
-    fn new(settings: &CaveChunkSettings, origin: Vec3, subdivisions: u32) -> Self {
-        let sample_count = 2_usize.pow(subdivisions);
-        let voxel_size = settings.size / sample_count as f32;
-        info!(
-            subdivisions = subdivisions,
-            sample_count = sample_count,
-            voxel_size = voxel_size
-        );
-
-        let (noise_samples, _min, _max) = simdnoise::NoiseBuilder::fbm_3d_offset(
-            origin.x / voxel_size,
-            sample_count,
-            origin.y / voxel_size,
-            sample_count,
-            origin.z / voxel_size,
-            sample_count,
+    pub fn from_samples(
+        settings: &CaveChunkSettings,
+        subdivisions: u32,
+        seams: u8,
+        noise_samples: Vec<f32>,
+        materials: Vec<u8>,
+    ) -> Self {
+        Self::from_compact(
+            settings,
+            subdivisions,
+            seams,
+            CaveChunkSamples::new(settings, noise_samples, materials),
         )
-        .with_seed(42)
-        .with_freq(settings.frequency * voxel_size)
-        .generate();
+    }
 
+    fn from_compact(
+        settings: &CaveChunkSettings,
+        subdivisions: u32,
+        seams: u8,
+        samples: CaveChunkSamples,
+    ) -> Self {
         CaveChunk {
             subdivisions,
-            noise_samples: Arc::new(RwLock::new(noise_samples)),
+            seams,
+            samples: Arc::new(RwLock::new(samples)),
             settings: settings.clone(),
         }
     }
+
+    /// Samples including the padding, `(2^subdivisions + 2)^3`.
+    pub fn sample_len(&self) -> usize {
+        (2_usize.pow(self.subdivisions) + 2).pow(3)
+    }
+
+    /// Makes `samples`, locked from this chunk at `origin`, changeable one by
+    /// one. Solid chunks may have lost their materials to the pre-pass, so
+    /// `generator` fills those in again.
+    pub fn expand<'a>(
+        &self,
+        samples: &'a mut CaveChunkSamples,
+        generator: &dyn CaveGenerator,
+        origin: Vec3,
+    ) -> (&'a mut [f32], &'a mut [u8]) {
+        if let CaveChunkSamples::Solid(_) = samples {
+            let grid = sample_grid(&self.settings, origin, self.subdivisions);
+            let mut density = vec![self.settings.threshold; grid.len()];
+            let mut materials = vec![0; grid.len()];
+            generator.generate(&self.settings, &grid, &mut density, &mut materials);
+            *samples = CaveChunkSamples::Mixed {
+                density: vec![self.settings.threshold + UNIFORM_DENSITY; grid.len()],
+                materials,
+            };
+        }
+        samples.expand(&self.settings, self.sample_len())
+    }
+
+    /// Generates the samples of the chunk at `origin`.
+    pub fn generate(
+        generator: &dyn CaveGenerator,
+        settings: &CaveChunkSettings,
+        origin: Vec3,
+        subdivisions: u32,
+        seams: u8,
+    ) -> Self {
+        let grid = sample_grid(settings, origin, subdivisions);
+        info!(
+            subdivisions = subdivisions,
+            sample_count = grid.length - 2,
+            voxel_size = grid.voxel_size
+        );
+
+        let worms = worms_near(settings, &grid);
+        if subdivisions > PREPASS_SUBDIVISIONS {
+            if let Some(samples) = Self::prepass(generator, settings, &grid, &worms) {
+                return Self::from_compact(settings, subdivisions, seams, samples);
+            }
+        }
+
+        let mut noise_samples = vec![settings.threshold; grid.len()];
+        let mut materials = vec![0; grid.len()];
+        generator.generate(settings, &grid, &mut noise_samples, &mut materials);
+        carve_worms(settings, &grid, &worms, &mut noise_samples);
+        Self::from_samples(settings, subdivisions, seams, noise_samples, materials)
+    }
+
+    /// Uniform samples for the chunk of `grid` if a coarse grid over it keeps
+    /// well away from the threshold and none of the `worms` near it carve
+    /// into it.
+    fn prepass(
+        generator: &dyn CaveGenerator,
+        settings: &CaveChunkSettings,
+        grid: &CaveSampleGrid,
+        worms: &[CaveWorm],
+    ) -> Option<CaveChunkSamples> {
+        let coarse = sample_grid(settings, grid.origin, PREPASS_SUBDIVISIONS);
+        let mut density = vec![settings.threshold; coarse.len()];
+        let mut materials = vec![0; coarse.len()];
+        generator.generate(settings, &coarse, &mut density, &mut materials);
+
+        // Between coarse samples density can stray from them by about as
+        // much as it changes from one to the next, on top of the detail
+        // `uniform_margin` is for.
+        let margin = settings.uniform_margin + largest_step(&density, coarse.length);
+        if density.iter().all(|d| *d < settings.threshold - margin) {
+            Some(CaveChunkSamples::Empty)
+        } else if density.iter().all(|d| *d > settings.threshold + margin) && worms.is_empty() {
+            Some(CaveChunkSamples::Solid(most_common(&materials)))
+        } else {
+            None
+        }
+    }
 }
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics, RegisterDiagnostic},
+    ecs::entity::Entities,
+    prelude::*,
+    utils::HashMap,
+};
+
+use super::{
+    chunk::CaveChunk,
+    mesh::CaveChunkVoxelsMeshedEvent,
+    schedule::{CaveChunkScheduler, CaveChunkStage},
+    voxelize::{CaveChunkVoxels, CaveVoxel},
+};
+
+/// Adds diagnostics of the chunk pipeline: jobs in every stage, time spent
+/// per chunk in each stage, triangles and sample memory.
+pub struct CaveDiagnosticsPlugin;
+
+impl Plugin for CaveDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(Self::QUEUED, "cave_chunks_queued", HISTORY))
+            .register_diagnostic(Diagnostic::new(
+                Self::GENERATING,
+                "cave_chunks_generating",
+                HISTORY,
+            ))
+            .register_diagnostic(Diagnostic::new(
+                Self::VOXELIZING,
+                "cave_chunks_voxelizing",
+                HISTORY,
+            ))
+            .register_diagnostic(Diagnostic::new(
+                Self::MESHING,
+                "cave_chunks_meshing",
+                HISTORY,
+            ))
+            .register_diagnostic(
+                Diagnostic::new(Self::GENERATE_TIME, "cave_chunk_generate_time", HISTORY)
+                    .with_suffix("ms"),
+            )
+            .register_diagnostic(
+                Diagnostic::new(Self::VOXELIZE_TIME, "cave_chunk_voxelize_time", HISTORY)
+                    .with_suffix("ms"),
+            )
+            .register_diagnostic(
+                Diagnostic::new(Self::MESH_TIME, "cave_chunk_mesh_time", HISTORY).with_suffix("ms"),
+            )
+            .register_diagnostic(Diagnostic::new(Self::TRIANGLES, "cave_triangles", HISTORY))
+            .register_diagnostic(
+                Diagnostic::new(Self::SAMPLE_MEMORY, "cave_sample_memory", HISTORY)
+                    .with_suffix("MiB"),
+            )
+            .register_diagnostic(
+                Diagnostic::new(Self::VOXEL_MEMORY, "cave_voxel_memory", HISTORY)
+                    .with_suffix("MiB"),
+            )
+            .add_systems(Update, Self::diagnostic_system);
+    }
+}
+
+const HISTORY: usize = 120;
+const MIB: f64 = 1024.0 * 1024.0;
+
+impl CaveDiagnosticsPlugin {
+    pub const QUEUED: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b01);
+    pub const GENERATING: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b02);
+    pub const VOXELIZING: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b03);
+    pub const MESHING: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b04);
+    /// Loading or generating the samples of a chunk, including carving.
+    pub const GENERATE_TIME: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b05);
+    pub const VOXELIZE_TIME: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b06);
+    pub const MESH_TIME: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b07);
+    /// Triangles in the meshes of every live chunk.
+    pub const TRIANGLES: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b08);
+    /// Density and material samples kept by chunks.
+    pub const SAMPLE_MEMORY: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b09);
+    pub const VOXEL_MEMORY: DiagnosticId =
+        DiagnosticId::from_u128(0x3f1c_6a2e_8d47_4b90_a5e3_1c7f_92d4_6b0a);
+
+    pub fn diagnostic_system(
+        mut diagnostics: Diagnostics,
+        scheduler: Res<CaveChunkScheduler>,
+        meshes: Res<Assets<Mesh>>,
+        entities: &Entities,
+        mut events: EventReader<CaveChunkVoxelsMeshedEvent>,
+        mut triangles: Local<HashMap<Entity, usize>>,
+        cave_chunks: Query<(&CaveChunk, Option<&CaveChunkVoxels>)>,
+    ) {
+        diagnostics.add_measurement(Self::QUEUED, || scheduler.queued_len() as f64);
+        let mut running = [0; 3];
+        for stage in scheduler.running_stages() {
+            match stage {
+                CaveChunkStage::Generating => running[0] += 1,
+                CaveChunkStage::Voxelizing => running[1] += 1,
+                CaveChunkStage::Meshing => running[2] += 1,
+                _ => {}
+            }
+        }
+        for (id, count) in [Self::GENERATING, Self::VOXELIZING, Self::MESHING]
+            .into_iter()
+            .zip(running)
+        {
+            diagnostics.add_measurement(id, || count as f64);
+        }
+
+        // Averages over the jobs that finished this frame.
+        let mut generate = Vec::new();
+        let (mut voxelize, mut mesh) = (Vec::new(), Vec::new());
+        for ev in events.iter() {
+            generate.extend(ev.timings.generate);
+            voxelize.push(ev.timings.voxelize);
+            mesh.push(ev.timings.mesh);
+            triangles.insert(
+                ev.entity,
+                ev.meshes
+                    .iter()
+                    .filter_map(|(_, handle)| meshes.get(handle)?.indices())
+                    .map(|indices| indices.len() / 3)
+                    .sum(),
+            );
+        }
+        for (id, durations) in [
+            (Self::GENERATE_TIME, generate),
+            (Self::VOXELIZE_TIME, voxelize),
+            (Self::MESH_TIME, mesh),
+        ] {
+            if !durations.is_empty() {
+                diagnostics.add_measurement(id, || {
+                    durations.iter().sum::<Duration>().as_secs_f64() * 1000.0
+                        / durations.len() as f64
+                });
+            }
+        }
+
+        triangles.retain(|entity, _| entities.contains(*entity));
+        diagnostics.add_measurement(Self::TRIANGLES, || triangles.values().sum::<usize>() as f64);
+
+        diagnostics.add_measurement(Self::SAMPLE_MEMORY, || {
+            cave_chunks
+                .iter()
+                .filter_map(|(cave_chunk, _)| Some(cave_chunk.samples.try_read().ok()?.heap_size()))
+                .sum::<usize>() as f64
+                / MIB
+        });
+        diagnostics.add_measurement(Self::VOXEL_MEMORY, || {
+            cave_chunks
+                .iter()
+                .filter_map(|(_, voxels)| {
+                    let data = voxels?.data.try_read().ok()?;
+                    Some(data.as_ref()?.capacity() * std::mem::size_of::<CaveVoxel>())
+                })
+                .sum::<usize>() as f64
+                / MIB
+        });
+    }
+}
@@ -0,0 +1,320 @@
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+
+use crate::{
+    controls::{Action, ActionState},
+    inspector::InspectorState,
+    player::Player,
+};
+
+use super::{
+    chunk::CaveChunk,
+    error::CaveChunkError,
+    generator::{CaveGenerator, CaveGenerators},
+    index::CaveChunkIndex,
+    region::CaveRegionStore,
+    schedule::CaveChunkScheduler,
+    stream::{CaveChunkKey, CaveStreamSettings, StreamedCaveChunks},
+    world::CaveWorld,
+};
+
+pub struct CaveEditPlugin;
+
+impl Plugin for CaveEditPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<CaveTool>()
+            .register_type::<CaveEditShape>()
+            .insert_resource(CaveTool::default())
+            .init_resource::<PendingCaveEdits>()
+            .add_event::<CaveEdit>()
+            .add_systems(
+                Update,
+                (
+                    // Clicks on inspector windows are not meant for the cave.
+                    player_tool
+                        .run_if(resource_exists::<ActionState>())
+                        .run_if(not(state_exists_and_equals(InspectorState::Active))),
+                    apply_cave_edits,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Samples within this many voxels of an edit's surface are reshaped.
+const EDIT_BAND: f32 = 2.0;
+/// Density change per voxel of distance from an edit's surface.
+const EDIT_SLOPE: f32 = 0.05;
+
+#[derive(Event, Debug, Clone)]
+pub struct CaveEdit {
+    pub position: Vec3,
+    pub shape: CaveEditShape,
+    pub action: CaveEditAction,
+}
+
+#[derive(Reflect, Debug, Clone, Copy)]
+pub enum CaveEditShape {
+    Sphere { radius: f32 },
+    Box { half_extents: Vec3 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CaveEditAction {
+    Dig,
+    /// Fill with a `CaveChunkSettings::palette` id.
+    Fill {
+        material: u8,
+    },
+}
+
+impl CaveEditShape {
+    /// Signed distance from the surface of the shape centred on the origin,
+    /// negative inside.
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match *self {
+            CaveEditShape::Sphere { radius } => p.length() - radius,
+            CaveEditShape::Box { half_extents } => {
+                let q = p.abs() - half_extents;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            }
+        }
+    }
+}
+
+impl CaveEdit {
+    fn half_extents(&self) -> Vec3 {
+        match self.shape {
+            CaveEditShape::Sphere { radius } => Vec3::splat(radius),
+            CaveEditShape::Box { half_extents } => half_extents,
+        }
+    }
+
+    fn distance(&self, point: Vec3) -> f32 {
+        self.shape.distance(point - self.position)
+    }
+
+    /// Reshapes the samples of `cave_chunk` around the edit, returning whether
+    /// any of them were in reach.
+    fn apply(
+        &self,
+        cave_chunk: &CaveChunk,
+        generator: &dyn CaveGenerator,
+        origin: Vec3,
+    ) -> Result<bool, CaveChunkError> {
+        let sample_count = 2_u32.pow(cave_chunk.subdivisions);
+        let voxel_size = cave_chunk.settings.size / sample_count as f32;
+        let length = sample_count as i32 + 2;
+        let threshold = cave_chunk.settings.threshold;
+
+        let reach = self.half_extents() + Vec3::splat(EDIT_BAND * voxel_size);
+        let min = ((self.position - reach - origin) / voxel_size + 1.0)
+            .ceil()
+            .as_ivec3()
+            .max(IVec3::ZERO);
+        let max = ((self.position + reach - origin) / voxel_size + 1.0)
+            .floor()
+            .as_ivec3()
+            .min(IVec3::splat(length - 1));
+        if min.cmpgt(max).any() {
+            return Ok(false);
+        }
+
+        let mut samples = cave_chunk.samples.try_write()?;
+        let (noise_samples, materials) = cave_chunk.expand(&mut samples, generator, origin);
+
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let i = IVec3::new(x, y, z);
+                    let distance = self.distance(origin + (i - 1).as_vec3() * voxel_size);
+                    if distance > EDIT_BAND * voxel_size {
+                        continue;
+                    }
+
+                    let index = (x + y * length + z * length * length) as usize;
+                    let slope = distance / voxel_size * EDIT_SLOPE;
+                    match self.action {
+                        CaveEditAction::Dig => {
+                            noise_samples[index] = noise_samples[index].min(threshold + slope);
+                        }
+                        CaveEditAction::Fill { material } => {
+                            noise_samples[index] = noise_samples[index].max(threshold - slope);
+                            if distance <= 0.0 {
+                                materials[index] = material;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Resource, Reflect, Debug)]
+#[reflect(Resource)]
+pub struct CaveTool {
+    pub shape: CaveEditShape,
+    pub reach: f32,
+    pub material: u8,
+}
+
+impl Default for CaveTool {
+    fn default() -> Self {
+        Self {
+            shape: CaveEditShape::Sphere { radius: 0.5 },
+            reach: 8.0,
+            material: 1,
+        }
+    }
+}
+
+fn player_tool(
+    actions: Res<ActionState>,
+    tool: Res<CaveTool>,
+    cave_world: CaveWorld,
+    player: Query<&GlobalTransform, With<Player>>,
+    mut edits: EventWriter<CaveEdit>,
+) {
+    let action = if actions.just_pressed(Action::Dig) {
+        CaveEditAction::Dig
+    } else if actions.just_pressed(Action::Fill) {
+        CaveEditAction::Fill {
+            material: tool.material,
+        }
+    } else {
+        return;
+    };
+
+    let player = if let Ok(player) = player.get_single() {
+        player
+    } else {
+        return;
+    };
+
+    if let Some(hit) = cave_world.raycast(player.translation(), player.forward(), tool.reach) {
+        edits.send(CaveEdit {
+            position: hit.position,
+            shape: tool.shape,
+            action,
+        });
+    }
+}
+
+/// Edits waiting for the chunks they reach to be generated or unlocked.
+#[derive(Resource, Default, Debug)]
+pub struct PendingCaveEdits(HashMap<CaveChunkKey, Vec<CaveEdit>>);
+
+impl PendingCaveEdits {
+    pub fn len(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(SystemParam)]
+struct CaveEditTargets<'w, 's> {
+    index: Res<'w, CaveChunkIndex>,
+    streamed: Res<'w, StreamedCaveChunks>,
+    stream_settings: Res<'w, CaveStreamSettings>,
+    cave_chunks: Query<'w, 's, (&'static CaveChunk, &'static Transform)>,
+}
+
+impl CaveEditTargets<'_, '_> {
+    /// Keys of the chunks `edit` may reach, generated or not.
+    fn keys(&self, edit: &CaveEdit) -> Vec<CaveChunkKey> {
+        let settings = &*self.stream_settings;
+        self.index
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| {
+                // The coarsest voxels reach furthest.
+                let voxel_size =
+                    settings.lod_size(key.lod) / 2_u32.pow(settings.min_subdivisions) as f32;
+                key.distance(settings, edit.position)
+                    <= edit.half_extents().length() + EDIT_BAND * voxel_size
+            })
+            .collect()
+    }
+
+    /// The chunk that holds `key` once streaming settles, rather than one
+    /// shown until its replacement is ready.
+    fn entity(&self, key: CaveChunkKey) -> Option<Entity> {
+        self.streamed
+            .loaded
+            .get(&key)
+            .map(|(entity, _)| *entity)
+            .or_else(|| self.index.get(key).map(|entry| entry.entity))
+    }
+}
+
+fn apply_cave_edits(
+    mut scheduler: ResMut<CaveChunkScheduler>,
+    mut pending: ResMut<PendingCaveEdits>,
+    targets: CaveEditTargets,
+    store: Res<CaveRegionStore>,
+    generators: Res<CaveGenerators>,
+    mut edits: EventReader<CaveEdit>,
+) {
+    for edit in edits.iter() {
+        for key in targets.keys(edit) {
+            pending.0.entry(key).or_default().push(edit.clone());
+        }
+    }
+
+    pending.0.retain(|key, edits| {
+        let entity = if let Some(entity) = targets.entity(*key) {
+            entity
+        } else {
+            return false;
+        };
+        let (cave_chunk, transform) = if let Ok(cave_chunk) = targets.cave_chunks.get(entity) {
+            cave_chunk
+        } else {
+            // Not generated yet.
+            return true;
+        };
+        let generator = if let Some(generator) = generators.get(&cave_chunk.settings.generator) {
+            generator
+        } else {
+            return false;
+        };
+
+        let mut edited = false;
+        let mut applied = 0;
+        let mut result = Ok(());
+        for edit in edits.iter() {
+            match edit.apply(cave_chunk, &*generator, transform.translation) {
+                Ok(reached) => edited |= reached,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+            applied += 1;
+        }
+        edits.drain(..applied);
+
+        if edited {
+            scheduler.remesh(entity);
+
+            // Only kept in memory here, the store writes it out later.
+            if let Err(err) = store.save_edited(&*generator, *key, cave_chunk) {
+                warn!(key = ?key, err = %err, "failed to store edited cave chunk");
+            }
+        }
+
+        match result {
+            // Tried again next frame.
+            Err(err) if err.is_retryable() => true,
+            Err(err) => {
+                warn!(key = ?key, err = %err, edits = edits.len(), "dropping cave edits");
+                false
+            }
+            Ok(()) => false,
+        }
+    });
+}
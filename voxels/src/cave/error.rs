@@ -0,0 +1,45 @@
+use std::{
+    fmt,
+    sync::{PoisonError, TryLockError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaveChunkError {
+    /// The chunk's data was locked for writing, usually by an edit. Worth
+    /// trying again.
+    Locked,
+    /// A thread panicked while holding the chunk's data.
+    Poisoned,
+}
+
+impl CaveChunkError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, CaveChunkError::Locked)
+    }
+}
+
+impl fmt::Display for CaveChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaveChunkError::Locked => write!(f, "cave chunk data is locked"),
+            CaveChunkError::Poisoned => write!(f, "cave chunk data is poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for CaveChunkError {}
+
+impl<T> From<TryLockError<T>> for CaveChunkError {
+    fn from(err: TryLockError<T>) -> Self {
+        match err {
+            TryLockError::WouldBlock => CaveChunkError::Locked,
+            TryLockError::Poisoned(_) => CaveChunkError::Poisoned,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for CaveChunkError {
+    fn from(_: PoisonError<T>) -> Self {
+        CaveChunkError::Poisoned
+    }
+}
@@ -0,0 +1,552 @@
+use std::{
+    fs,
+    hash::Hasher,
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use simdnoise::{CellReturnType, NoiseBuilder};
+
+use super::{
+    chunk::{CaveChunkSettings, CaveNoiseKind},
+    edit::CaveEditShape,
+    region::Reader,
+};
+
+pub struct CaveGeneratorPlugin;
+
+impl Plugin for CaveGeneratorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CaveGenerators>()
+            .add_cave_generator(NOISE_GENERATOR, NoiseGenerator);
+    }
+}
+
+/// Name the `NoiseGenerator` is registered under.
+pub const NOISE_GENERATOR: &str = "noise";
+
+/// Brings simdnoise's 2d gradient noise, which stays within about ±0.022,
+/// to ±1.
+const BIOME_NOISE_SCALE: f32 = 45.0;
+/// Density change per voxel of distance from a CSG shape's surface.
+const CSG_SLOPE: f32 = 0.05;
+/// Distance of volume densities from the threshold.
+const VOLUME_DENSITY: f32 = 0.1;
+
+/// Fills chunk samples. Implementations must be deterministic, as chunks are
+/// generated independently and neighbours have to agree on shared samples.
+pub trait CaveGenerator: Send + Sync + 'static {
+    /// Writes the density and palette id of every sample of `grid`. Samples
+    /// denser than `settings.threshold` are solid.
+    fn generate(
+        &self,
+        settings: &CaveChunkSettings,
+        grid: &CaveSampleGrid,
+        density: &mut [f32],
+        materials: &mut [u8],
+    );
+
+    /// Stable hash of everything besides `CaveChunkSettings` that changes
+    /// what `generate` writes, so that stored chunks are only read back by
+    /// the same generator with the same parameters.
+    fn fingerprint(&self) -> u64;
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is the same across runs, platforms
+/// and Rust versions, for fingerprints kept on disk.
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Samples of a chunk, `length` per axis, x first. Sample (1, 1, 1) is at the
+/// chunk origin, the others padding shared with the neighbouring chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct CaveSampleGrid {
+    pub origin: Vec3,
+    pub voxel_size: f32,
+    pub length: usize,
+}
+
+impl CaveSampleGrid {
+    pub fn len(&self) -> usize {
+        self.length.pow(3)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn position(&self, index: usize) -> Vec3 {
+        let (x, y, z) = (
+            index % self.length,
+            index / self.length % self.length,
+            index / self.length.pow(2),
+        );
+        self.origin + (Vec3::new(x as f32, y as f32, z as f32) - 1.0) * self.voxel_size
+    }
+}
+
+/// Generators by name, for `CaveChunkSettings::generator` to pick from.
+#[derive(Resource, Default, Clone)]
+pub struct CaveGenerators(HashMap<String, Arc<dyn CaveGenerator>>);
+
+impl CaveGenerators {
+    pub fn insert(&mut self, name: impl Into<String>, generator: impl CaveGenerator) {
+        self.0.insert(name.into(), Arc::new(generator));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn CaveGenerator>> {
+        self.0.get(name).cloned()
+    }
+}
+
+pub trait AddCaveGenerator {
+    /// Registers a generator for `CaveChunkSettings::generator` to select.
+    fn add_cave_generator(
+        &mut self,
+        name: impl Into<String>,
+        generator: impl CaveGenerator,
+    ) -> &mut Self;
+}
+
+impl AddCaveGenerator for App {
+    fn add_cave_generator(
+        &mut self,
+        name: impl Into<String>,
+        generator: impl CaveGenerator,
+    ) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(CaveGenerators::default)
+            .insert(name, generator);
+        self
+    }
+}
+
+/// Thresholded noise, blended between the strata of `CaveChunkSettings`.
+pub struct NoiseGenerator;
+
+impl CaveGenerator for NoiseGenerator {
+    fn generate(
+        &self,
+        settings: &CaveChunkSettings,
+        grid: &CaveSampleGrid,
+        density: &mut [f32],
+        materials: &mut [u8],
+    ) {
+        let (origin, voxel_size, sample_count) = (grid.origin, grid.voxel_size, grid.length);
+        let (x, y, z) = (
+            origin.x / voxel_size - 1.0,
+            origin.y / voxel_size - 1.0,
+            origin.z / voxel_size - 1.0,
+        );
+        let noise = |kind: CaveNoiseKind, frequency: f32| {
+            let frequency = frequency * voxel_size;
+            let (samples, _min, _max) = match kind {
+                CaveNoiseKind::Fbm => {
+                    NoiseBuilder::fbm_3d_offset(x, sample_count, y, sample_count, z, sample_count)
+                        .with_seed(settings.seed)
+                        .with_freq(frequency)
+                        .with_octaves(settings.octaves)
+                        .with_lacunarity(settings.lacunarity)
+                        .with_gain(settings.gain)
+                        .generate()
+                }
+                CaveNoiseKind::Ridge => {
+                    NoiseBuilder::ridge_3d_offset(x, sample_count, y, sample_count, z, sample_count)
+                        .with_seed(settings.seed)
+                        .with_freq(frequency)
+                        .with_octaves(settings.octaves)
+                        .with_lacunarity(settings.lacunarity)
+                        .with_gain(settings.gain)
+                        .generate()
+                }
+                CaveNoiseKind::Turbulence => NoiseBuilder::turbulence_3d_offset(
+                    x,
+                    sample_count,
+                    y,
+                    sample_count,
+                    z,
+                    sample_count,
+                )
+                .with_seed(settings.seed)
+                .with_freq(frequency)
+                .with_octaves(settings.octaves)
+                .with_lacunarity(settings.lacunarity)
+                .with_gain(settings.gain)
+                .generate(),
+                CaveNoiseKind::Cellular => NoiseBuilder::cellular_3d_offset(
+                    x,
+                    sample_count,
+                    y,
+                    sample_count,
+                    z,
+                    sample_count,
+                )
+                .with_seed(settings.seed)
+                .with_freq(frequency)
+                .generate(),
+            };
+            samples
+        };
+
+        let (material_samples, _min, _max) =
+            NoiseBuilder::cellular_3d_offset(x, sample_count, y, sample_count, z, sample_count)
+                .with_seed(settings.seed.wrapping_add(1))
+                .with_freq(settings.material_frequency * voxel_size)
+                .with_return_type(CellReturnType::CellValue)
+                .generate();
+
+        // Stratum weights of every sample, by its depth moved up or down by
+        // the biome noise of its column.
+        let strata = settings.all_strata();
+        let biome = if settings.strata.is_empty() {
+            vec![0.0; sample_count * sample_count]
+        } else {
+            let (biome, _min, _max) =
+                NoiseBuilder::gradient_2d_offset(x, sample_count, z, sample_count)
+                    .with_seed(settings.seed.wrapping_add(2))
+                    .with_freq(settings.biome_frequency * voxel_size)
+                    .generate();
+            biome
+                .into_iter()
+                .map(|sample| (sample * BIOME_NOISE_SCALE).clamp(-1.0, 1.0))
+                .collect()
+        };
+        let mut weights = vec![0.0; sample_count.pow(3) * strata.len()];
+        for (i, weights) in weights.chunks_mut(strata.len()).enumerate() {
+            let (column, row) = (
+                i % sample_count + i / sample_count.pow(2) * sample_count,
+                i / sample_count % sample_count,
+            );
+            let depth = -(origin.y + (row as f32 - 1.0) * voxel_size)
+                + biome[column] * settings.biome_depth_shift;
+            settings.strata_weights(&strata, depth, weights);
+        }
+        let active: Vec<_> = (0..strata.len())
+            .filter(|s| {
+                weights
+                    .chunks(strata.len())
+                    .any(|weights| weights[*s] > 0.0)
+            })
+            .collect();
+
+        let (samples, palette_ids) = if let [only] = active[..] {
+            let stratum = &strata[only];
+            let palette_weights = stratum.palette_weights(&settings.palette);
+            let mut samples = noise(stratum.noise, stratum.frequency);
+            if only != 0 {
+                let offset = settings.threshold - stratum.threshold;
+                samples.iter_mut().for_each(|sample| *sample += offset);
+            }
+            let materials = material_samples
+                .into_iter()
+                .map(|sample| CaveChunkSettings::weighted_palette_id(&palette_weights, sample))
+                .collect();
+            (samples, materials)
+        } else {
+            // Blend the densities relative to each stratum's threshold, and
+            // the material weights.
+            let layers: Vec<_> = active
+                .iter()
+                .map(|s| {
+                    let stratum = &strata[*s];
+                    (
+                        *s,
+                        noise(stratum.noise, stratum.frequency),
+                        stratum.threshold,
+                        stratum.palette_weights(&settings.palette),
+                    )
+                })
+                .collect();
+            let mut samples = vec![settings.threshold; material_samples.len()];
+            let mut materials = Vec::with_capacity(material_samples.len());
+            let mut palette_weights = vec![0.0; settings.palette.len()];
+            for (i, sample) in samples.iter_mut().enumerate() {
+                palette_weights.iter_mut().for_each(|weight| *weight = 0.0);
+                for (s, layer_samples, threshold, layer_weights) in &layers {
+                    let weight = weights[i * strata.len() + s];
+                    *sample += weight * (layer_samples[i] - threshold);
+                    for (total, layer_weight) in palette_weights.iter_mut().zip(layer_weights) {
+                        *total += weight * layer_weight;
+                    }
+                }
+                materials.push(CaveChunkSettings::weighted_palette_id(
+                    &palette_weights,
+                    material_samples[i],
+                ));
+            }
+            (samples, materials)
+        };
+        density.copy_from_slice(&samples);
+        materials.copy_from_slice(&palette_ids);
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        hasher.write(NOISE_GENERATOR.as_bytes());
+        hasher.finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOp {
+    /// Fills the shape with rock.
+    Union,
+    /// Carves the shape out.
+    Subtract,
+    /// Keeps only the rock inside the shape.
+    Intersect,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CsgShape {
+    pub op: CsgOp,
+    pub shape: CaveEditShape,
+    pub position: Vec3,
+    /// Palette id of rock filled in by a union.
+    pub material: u8,
+}
+
+/// Signed distance primitives combined, in order, with another generator's
+/// output, for hand-placed chambers and passages.
+pub struct CsgGenerator {
+    pub base: Arc<dyn CaveGenerator>,
+    pub shapes: Vec<CsgShape>,
+}
+
+impl CsgGenerator {
+    pub fn new(base: impl CaveGenerator) -> Self {
+        Self {
+            base: Arc::new(base),
+            shapes: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, op: CsgOp, shape: CaveEditShape, position: Vec3, material: u8) -> Self {
+        self.shapes.push(CsgShape {
+            op,
+            shape,
+            position,
+            material,
+        });
+        self
+    }
+}
+
+impl CaveGenerator for CsgGenerator {
+    fn generate(
+        &self,
+        settings: &CaveChunkSettings,
+        grid: &CaveSampleGrid,
+        density: &mut [f32],
+        materials: &mut [u8],
+    ) {
+        self.base.generate(settings, grid, density, materials);
+
+        let threshold = settings.threshold;
+        for (i, (density, material)) in density.iter_mut().zip(materials).enumerate() {
+            let position = grid.position(i);
+            for shape in &self.shapes {
+                let distance = shape.shape.distance(position - shape.position);
+                let inside = threshold - distance / grid.voxel_size * CSG_SLOPE;
+                match shape.op {
+                    CsgOp::Union => {
+                        if inside > *density {
+                            *density = inside;
+                            *material = shape.material;
+                        }
+                    }
+                    CsgOp::Subtract => *density = density.min(2.0 * threshold - inside),
+                    CsgOp::Intersect => *density = density.min(inside),
+                }
+            }
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        hasher.write(b"csg");
+        hasher.write(&self.base.fingerprint().to_le_bytes());
+        for shape in &self.shapes {
+            hasher.write(&[shape.op as u8, shape.material]);
+            match shape.shape {
+                CaveEditShape::Sphere { radius } => {
+                    hasher.write(&[0]);
+                    hasher.write(&radius.to_le_bytes());
+                }
+                CaveEditShape::Box { half_extents } => {
+                    hasher.write(&[1]);
+                    write_vec3(&mut hasher, half_extents);
+                }
+            }
+            write_vec3(&mut hasher, shape.position);
+        }
+        hasher.finish()
+    }
+}
+
+/// A MagicaVoxel model stamped over another generator's output, replacing
+/// both rock and air inside its bounds.
+pub struct VolumeGenerator {
+    pub base: Arc<dyn CaveGenerator>,
+    /// World position of the model's minimum corner.
+    pub origin: Vec3,
+    /// World size of one model voxel.
+    pub voxel_size: f32,
+    volume: CaveVolume,
+}
+
+/// Palette indices of a voxel model, 0 being empty, y up.
+struct CaveVolume {
+    size: IVec3,
+    voxels: Vec<u8>,
+    /// Hash of `size` and `voxels`, kept as models can be large.
+    fingerprint: u64,
+}
+
+impl VolumeGenerator {
+    /// Loads the first model of a MagicaVoxel `.vox` file. Its palette index
+    /// `n` becomes palette id `n`, wrapping around the cave palette if it
+    /// has been filled in.
+    pub fn load(
+        path: impl AsRef<Path>,
+        base: impl CaveGenerator,
+        origin: Vec3,
+        voxel_size: f32,
+    ) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut bytes)?;
+        Ok(Self {
+            base: Arc::new(base),
+            origin,
+            voxel_size,
+            volume: read_vox(&bytes)?,
+        })
+    }
+}
+
+impl CaveGenerator for VolumeGenerator {
+    fn generate(
+        &self,
+        settings: &CaveChunkSettings,
+        grid: &CaveSampleGrid,
+        density: &mut [f32],
+        materials: &mut [u8],
+    ) {
+        self.base.generate(settings, grid, density, materials);
+
+        let size = self.volume.size;
+        for (i, (density, material)) in density.iter_mut().zip(materials).enumerate() {
+            let cell = ((grid.position(i) - self.origin) / self.voxel_size)
+                .floor()
+                .as_ivec3();
+            if cell.cmplt(IVec3::ZERO).any() || cell.cmpge(size).any() {
+                continue;
+            }
+
+            let index =
+                self.volume.voxels[(cell.x + cell.y * size.x + cell.z * size.x * size.y) as usize];
+            if index == 0 {
+                *density = settings.threshold - VOLUME_DENSITY;
+            } else {
+                *density = settings.threshold + VOLUME_DENSITY;
+                *material = match settings.palette.len() {
+                    0 => index,
+                    len => ((index as usize - 1) % len) as u8 + 1,
+                };
+            }
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        hasher.write(b"volume");
+        hasher.write(&self.base.fingerprint().to_le_bytes());
+        write_vec3(&mut hasher, self.origin);
+        hasher.write(&self.voxel_size.to_le_bytes());
+        hasher.write(&self.volume.fingerprint.to_le_bytes());
+        hasher.finish()
+    }
+}
+
+fn write_vec3(hasher: &mut StableHasher, v: Vec3) {
+    for c in v.to_array() {
+        hasher.write(&c.to_le_bytes());
+    }
+}
+
+fn read_vox(bytes: &[u8]) -> io::Result<CaveVolume> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut reader = Reader(bytes);
+    if reader.take(4)? != b"VOX " {
+        return Err(invalid("not a MagicaVoxel file"));
+    }
+    reader.u32()?;
+
+    // Chunks are flattened, as the MAIN chunk only has children.
+    let mut size = None;
+    while !reader.0.is_empty() {
+        let id = reader.take(4)?;
+        let content_len = reader.u32()? as usize;
+        let children_len = reader.u32()? as usize;
+        let mut content = Reader(reader.take(content_len)?);
+        match id {
+            b"MAIN" => continue,
+            b"SIZE" if size.is_none() => {
+                // MagicaVoxel is z up.
+                let (x, y, z) = (content.u32()?, content.u32()?, content.u32()?);
+                size = Some(IVec3::new(x as i32, z as i32, y as i32));
+            }
+            b"XYZI" => {
+                let size = size.ok_or_else(|| invalid("voxels before model size"))?;
+                let mut voxels = vec![0; (size.x * size.y * size.z) as usize];
+                for _ in 0..content.u32()? {
+                    let voxel = content.take(4)?;
+                    // A rotation about x rather than a swap of y and z, which
+                    // would mirror the model.
+                    let (x, y, z) = (
+                        voxel[0] as i32,
+                        voxel[2] as i32,
+                        size.z - 1 - voxel[1] as i32,
+                    );
+                    if x < size.x && y < size.y && z >= 0 && z < size.z {
+                        voxels[(x + y * size.x + z * size.x * size.y) as usize] = voxel[3];
+                    }
+                }
+                let mut hasher = StableHasher::default();
+                for c in size.to_array() {
+                    hasher.write(&c.to_le_bytes());
+                }
+                hasher.write(&voxels);
+                return Ok(CaveVolume {
+                    size,
+                    voxels,
+                    fingerprint: hasher.finish(),
+                });
+            }
+            _ => {}
+        }
+        reader.take(children_len)?;
+    }
+    Err(invalid("no model in file"))
+}
@@ -0,0 +1,173 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{
+    schedule::CaveChunkStage,
+    stream::{CaveChunkKey, CaveStreamSettings},
+};
+
+pub struct CaveIndexPlugin;
+
+impl Plugin for CaveIndexPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CaveChunkIndex::default());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CaveChunkEntry {
+    pub entity: Entity,
+    pub stage: CaveChunkStage,
+}
+
+/// Every spawned chunk entity by its key, with where it is in the pipeline.
+#[derive(Resource, Default, Debug)]
+pub struct CaveChunkIndex {
+    chunks: HashMap<CaveChunkKey, CaveChunkEntry>,
+    keys: HashMap<Entity, CaveChunkKey>,
+}
+
+impl CaveChunkIndex {
+    pub fn get(&self, key: CaveChunkKey) -> Option<&CaveChunkEntry> {
+        self.chunks.get(&key)
+    }
+
+    pub fn key(&self, entity: Entity) -> Option<CaveChunkKey> {
+        self.keys.get(&entity).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&CaveChunkKey, &CaveChunkEntry)> {
+        self.chunks.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The finest chunk containing the point.
+    pub fn at(
+        &self,
+        settings: &CaveStreamSettings,
+        point: Vec3,
+    ) -> Option<(CaveChunkKey, &CaveChunkEntry)> {
+        (0..=settings.max_lod).find_map(|lod| {
+            let key = CaveChunkKey {
+                lod,
+                coord: (point / settings.lod_size(lod)).floor().as_ivec3(),
+            };
+            Some((key, self.chunks.get(&key)?))
+        })
+    }
+
+    /// Chunks sharing a face with the chunk, whether of the same, a coarser
+    /// or a finer lod.
+    pub fn neighbours(&self, key: CaveChunkKey) -> Vec<(CaveChunkKey, &CaveChunkEntry)> {
+        let mut neighbours = Vec::new();
+        for (axis, dir) in IVec3::AXES.iter().enumerate() {
+            for dir in [-*dir, *dir] {
+                let across = CaveChunkKey {
+                    lod: key.lod,
+                    coord: key.coord + dir,
+                };
+                if let Some(coarser) = self.ancestor(across) {
+                    neighbours.push(coarser);
+                } else {
+                    self.face_descendants(across, axis, dir[axis] < 0, &mut neighbours);
+                }
+            }
+        }
+        neighbours
+    }
+
+    /// The chunk itself or the nearest coarser one containing it.
+    fn ancestor(&self, mut key: CaveChunkKey) -> Option<(CaveChunkKey, &CaveChunkEntry)> {
+        loop {
+            if let Some(entry) = self.chunks.get(&key) {
+                return Some((key, entry));
+            }
+            key = CaveChunkKey {
+                lod: key.lod + 1,
+                coord: IVec3::new(
+                    key.coord.x.div_euclid(2),
+                    key.coord.y.div_euclid(2),
+                    key.coord.z.div_euclid(2),
+                ),
+            };
+            if key.lod > 31 {
+                return None;
+            }
+        }
+    }
+
+    /// Finer chunks inside `key` on its face along `axis`, the far one when
+    /// `positive`.
+    fn face_descendants<'a>(
+        &'a self,
+        key: CaveChunkKey,
+        axis: usize,
+        positive: bool,
+        found: &mut Vec<(CaveChunkKey, &'a CaveChunkEntry)>,
+    ) {
+        if key.lod == 0 {
+            return;
+        }
+        for i in 0..8 {
+            let offset = IVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+            if (offset[axis] == 1) != positive {
+                continue;
+            }
+            let child = CaveChunkKey {
+                lod: key.lod - 1,
+                coord: key.coord * 2 + offset,
+            };
+            if let Some(entry) = self.chunks.get(&child) {
+                found.push((child, entry));
+            } else {
+                self.face_descendants(child, axis, positive, found);
+            }
+        }
+    }
+}
+
+/// Follows chunk entities as they spawn, change stage and despawn.
+pub fn index_cave_chunks(
+    mut index: ResMut<CaveChunkIndex>,
+    mut removed: RemovedComponents<CaveChunkKey>,
+    cave_chunks: Query<(Entity, &CaveChunkKey, &CaveChunkStage), Changed<CaveChunkStage>>,
+) {
+    let index = &mut *index;
+    for entity in removed.iter() {
+        if let Some(key) = index.keys.remove(&entity) {
+            // The key may have been taken over by a replacement already.
+            if index
+                .chunks
+                .get(&key)
+                .is_some_and(|entry| entry.entity == entity)
+            {
+                index.chunks.remove(&key);
+            }
+        }
+    }
+
+    for (entity, key, stage) in &cave_chunks {
+        index.keys.insert(entity, *key);
+        // A chunk being replaced stays indexed until its replacement is ready.
+        let replacing = index
+            .chunks
+            .get(key)
+            .is_some_and(|entry| entry.entity != entity && entry.stage == CaveChunkStage::Ready);
+        if replacing && *stage != CaveChunkStage::Ready {
+            continue;
+        }
+        index.chunks.insert(
+            *key,
+            CaveChunkEntry {
+                entity,
+                stage: *stage,
+            },
+        );
+    }
+}
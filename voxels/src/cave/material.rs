@@ -0,0 +1,277 @@
+use bevy::{
+    asset::load_internal_asset,
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    render::{
+        render_resource::{
+            AddressMode, AsBindGroup, Extent3d, FilterMode, SamplerDescriptor, ShaderRef,
+            TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension,
+        },
+        texture::ImageSampler,
+        RenderPlugin,
+    },
+};
+
+use super::chunk::CaveChunkSettings;
+
+pub struct CaveMaterialPlugin;
+
+impl Plugin for CaveMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<CaveMaterialSettings>()
+            .insert_resource(CaveMaterialSettings::default())
+            .add_asset::<Image>()
+            .add_systems(Update, update_cave_materials);
+
+        // Without a renderer, as in headless runs, materials are plain assets.
+        if app.is_plugin_added::<RenderPlugin>() {
+            load_internal_asset!(
+                app,
+                CAVE_MATERIAL_SHADER_HANDLE,
+                "material.wgsl",
+                Shader::from_wgsl
+            );
+            app.add_plugins(MaterialPlugin::<CaveMaterial>::default());
+        } else {
+            app.add_asset::<CaveMaterial>();
+        }
+    }
+}
+
+const CAVE_MATERIAL_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x6a2f_1c3e_9b47_d805);
+
+/// Texture pixels per side of every layer.
+const TEXTURE_SIZE: u32 = 128;
+
+#[derive(Resource, Reflect, Debug)]
+#[reflect(Resource)]
+pub struct CaveMaterialSettings {
+    /// Texture repeats per world unit.
+    pub scale: f32,
+    /// Higher values narrow the blend between projections at slanted faces.
+    pub sharpness: f32,
+}
+
+impl Default for CaveMaterialSettings {
+    fn default() -> Self {
+        Self {
+            scale: 0.5,
+            sharpness: 4.0,
+        }
+    }
+}
+
+/// Triplanar projection of albedo, normal and roughness texture arrays in
+/// world space. Each palette entry samples its own `layer` of the arrays.
+#[derive(AsBindGroup, TypeUuid, TypePath, Debug, Clone)]
+#[uuid = "0d5f6a57-3c4b-4f1e-9a3e-7c2d1b8e6f40"]
+pub struct CaveMaterial {
+    #[uniform(0)]
+    pub base_color: Color,
+    #[uniform(0)]
+    pub metallic: f32,
+    #[uniform(0)]
+    pub perceptual_roughness: f32,
+    #[uniform(0)]
+    pub scale: f32,
+    #[uniform(0)]
+    pub sharpness: f32,
+    #[uniform(0)]
+    pub layer: u32,
+    #[texture(1, dimension = "2d_array")]
+    #[sampler(2)]
+    pub albedo: Handle<Image>,
+    #[texture(3, dimension = "2d_array")]
+    #[sampler(4)]
+    pub normal: Handle<Image>,
+    #[texture(5, dimension = "2d_array")]
+    #[sampler(6)]
+    pub roughness: Handle<Image>,
+}
+
+impl CaveMaterial {
+    pub fn new(base_color: Color, metallic: f32, perceptual_roughness: f32) -> Self {
+        let settings = CaveMaterialSettings::default();
+        Self {
+            base_color,
+            metallic,
+            perceptual_roughness,
+            scale: settings.scale,
+            sharpness: settings.sharpness,
+            layer: 0,
+            albedo: default(),
+            normal: default(),
+            roughness: default(),
+        }
+    }
+}
+
+impl Material for CaveMaterial {
+    fn fragment_shader() -> ShaderRef {
+        CAVE_MATERIAL_SHADER_HANDLE.typed().into()
+    }
+}
+
+/// Texture arrays with one procedural rock layer per palette entry.
+pub struct CaveTextures {
+    pub albedo: Handle<Image>,
+    pub normal: Handle<Image>,
+    pub roughness: Handle<Image>,
+}
+
+impl CaveTextures {
+    pub fn generate(images: &mut Assets<Image>, layers: u32) -> Self {
+        let (mut albedo, mut normal, mut roughness) = (Vec::new(), Vec::new(), Vec::new());
+        for layer in 0..layers {
+            let height = rock_height(layer);
+            let at = |x: i32, y: i32| {
+                let size = TEXTURE_SIZE as i32;
+                height[(x.rem_euclid(size) + y.rem_euclid(size) * size) as usize]
+            };
+
+            let mut levels = [Vec::new(), Vec::new(), Vec::new()];
+            for y in 0..TEXTURE_SIZE as i32 {
+                for x in 0..TEXTURE_SIZE as i32 {
+                    let h = at(x, y);
+                    let shade = 0.45 + 0.55 * h;
+                    levels[0].push([shade, shade, shade, 1.0]);
+
+                    let gradient =
+                        Vec2::new(at(x + 1, y) - at(x - 1, y), at(x, y + 1) - at(x, y - 1));
+                    let n = Vec3::new(-gradient.x * 6.0, -gradient.y * 6.0, 1.0).normalize();
+                    levels[1].push([n.x * 0.5 + 0.5, n.y * 0.5 + 0.5, n.z * 0.5 + 0.5, 1.0]);
+
+                    let r = 0.6 + 0.4 * (1.0 - h);
+                    levels[2].push([r, r, r, 1.0]);
+                }
+            }
+
+            for (level, data) in levels
+                .into_iter()
+                .zip([&mut albedo, &mut normal, &mut roughness])
+            {
+                push_mip_chain(data, level);
+            }
+        }
+
+        Self {
+            albedo: images.add(texture_array(albedo, layers, TextureFormat::Rgba8UnormSrgb)),
+            normal: images.add(texture_array(normal, layers, TextureFormat::Rgba8Unorm)),
+            roughness: images.add(texture_array(roughness, layers, TextureFormat::Rgba8Unorm)),
+        }
+    }
+}
+
+fn mip_level_count() -> u32 {
+    TEXTURE_SIZE.ilog2() + 1
+}
+
+/// Appends `level` and its box filtered mips, in the layer major order
+/// textures are uploaded in.
+fn push_mip_chain(data: &mut Vec<u8>, mut level: Vec<[f32; 4]>) {
+    let mut size = TEXTURE_SIZE as usize;
+    loop {
+        data.extend(
+            level
+                .iter()
+                .flatten()
+                .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8),
+        );
+        if size == 1 {
+            return;
+        }
+
+        let half = size / 2;
+        level = (0..half * half)
+            .map(|i| {
+                let (x, y) = (i % half * 2, i / half * 2);
+                let mut sum = [0.0; 4];
+                for p in [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)] {
+                    for (s, c) in sum.iter_mut().zip(level[p.0 + p.1 * size]) {
+                        *s += c / 4.0;
+                    }
+                }
+                sum
+            })
+            .collect();
+        size = half;
+    }
+}
+
+fn texture_array(data: Vec<u8>, layers: u32, format: TextureFormat) -> Image {
+    let size = Extent3d {
+        width: TEXTURE_SIZE,
+        height: TEXTURE_SIZE,
+        depth_or_array_layers: layers,
+    };
+    let mut image = Image::new_fill(size, TextureDimension::D2, &[0; 4], format);
+    image.data = data;
+    image.texture_descriptor.mip_level_count = mip_level_count();
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+    image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
+        address_mode_u: AddressMode::Repeat,
+        address_mode_v: AddressMode::Repeat,
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        mipmap_filter: FilterMode::Linear,
+        ..default()
+    });
+    image
+}
+
+/// Tileable fbm of periodic value noise in [0, 1], different for every layer.
+fn rock_height(layer: u32) -> Vec<f32> {
+    let hash = |x: u32, y: u32, octave: u32| {
+        let mut h = x
+            .wrapping_mul(0x8da6_b343)
+            .wrapping_add(y.wrapping_mul(0xd816_3841))
+            .wrapping_add((layer * 8 + octave).wrapping_mul(0xcb1a_b31f));
+        h ^= h >> 13;
+        h = h.wrapping_mul(0x5bd1_e995);
+        h ^= h >> 15;
+        h as f32 / u32::MAX as f32
+    };
+
+    let mut height = vec![0.0; (TEXTURE_SIZE * TEXTURE_SIZE) as usize];
+    let mut amplitude = 0.5;
+    let mut total = 0.0;
+    for octave in 0..5 {
+        let period = 4 << octave;
+        let cell = TEXTURE_SIZE as f32 / period as f32;
+        for (i, h) in height.iter_mut().enumerate() {
+            let x = (i as u32 % TEXTURE_SIZE) as f32 / cell;
+            let y = (i as u32 / TEXTURE_SIZE) as f32 / cell;
+            let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+            let (tx, ty) = (x.fract(), y.fract());
+            let (tx, ty) = (tx * tx * (3.0 - 2.0 * tx), ty * ty * (3.0 - 2.0 * ty));
+            let corner = |dx: u32, dy: u32| hash((x0 + dx) % period, (y0 + dy) % period, octave);
+            let top = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * tx;
+            let bottom = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * tx;
+            *h += (top + (bottom - top) * ty) * amplitude;
+        }
+        total += amplitude;
+        amplitude *= 0.5;
+    }
+    height.iter_mut().for_each(|h| *h /= total);
+    height
+}
+
+fn update_cave_materials(
+    settings: Res<CaveMaterialSettings>,
+    chunk_settings: Res<CaveChunkSettings>,
+    mut materials: ResMut<Assets<CaveMaterial>>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    for entry in &chunk_settings.palette {
+        if let Some(material) = materials.get_mut(&entry.material) {
+            material.scale = settings.scale;
+            material.sharpness = settings.sharpness;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+#import bevy_pbr::mesh_vertex_output MeshVertexOutput
+#import bevy_pbr::mesh_view_bindings view
+#import bevy_pbr::mesh_bindings mesh
+#import bevy_pbr::pbr_functions as pbr_functions
+#import bevy_core_pipeline::tonemapping tone_mapping
+
+struct CaveMaterial {
+    base_color: vec4<f32>,
+    metallic: f32,
+    perceptual_roughness: f32,
+    scale: f32,
+    sharpness: f32,
+    layer: u32,
+};
+
+@group(1) @binding(0) var<uniform> material: CaveMaterial;
+@group(1) @binding(1) var albedo_texture: texture_2d_array<f32>;
+@group(1) @binding(2) var albedo_sampler: sampler;
+@group(1) @binding(3) var normal_texture: texture_2d_array<f32>;
+@group(1) @binding(4) var normal_sampler: sampler;
+@group(1) @binding(5) var roughness_texture: texture_2d_array<f32>;
+@group(1) @binding(6) var roughness_sampler: sampler;
+
+fn tangent_normal(uv: vec2<f32>, layer: i32) -> vec3<f32> {
+    return textureSample(normal_texture, normal_sampler, uv, layer).xyz * 2.0 - 1.0;
+}
+
+@fragment
+fn fragment(
+    in: MeshVertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> @location(0) vec4<f32> {
+    let n = normalize(in.world_normal);
+    var weights = pow(abs(n), vec3(material.sharpness));
+    weights = weights / (weights.x + weights.y + weights.z);
+
+    // Project the textures along each world axis and blend by the normal.
+    let position = in.world_position.xyz * material.scale;
+    let uv_x = position.zy;
+    let uv_y = position.xz;
+    let uv_z = position.xy;
+    let layer = i32(material.layer);
+
+    let albedo = textureSample(albedo_texture, albedo_sampler, uv_x, layer) * weights.x
+        + textureSample(albedo_texture, albedo_sampler, uv_y, layer) * weights.y
+        + textureSample(albedo_texture, albedo_sampler, uv_z, layer) * weights.z;
+    let roughness = textureSample(roughness_texture, roughness_sampler, uv_x, layer).r * weights.x
+        + textureSample(roughness_texture, roughness_sampler, uv_y, layer).r * weights.y
+        + textureSample(roughness_texture, roughness_sampler, uv_z, layer).r * weights.z;
+
+    // Whiteout blend of the projected tangent space normals.
+    let tn_x = tangent_normal(uv_x, layer);
+    let tn_y = tangent_normal(uv_y, layer);
+    let tn_z = tangent_normal(uv_z, layer);
+    let n_x = vec3(tn_x.xy + n.zy, abs(tn_x.z) * n.x);
+    let n_y = vec3(tn_y.xy + n.xz, abs(tn_y.z) * n.y);
+    let n_z = vec3(tn_z.xy + n.xy, abs(tn_z.z) * n.z);
+    let N = normalize(n_x.zyx * weights.x + n_y.xzy * weights.y + n_z.xyz * weights.z);
+
+    var pbr_input = pbr_functions::pbr_input_new();
+    pbr_input.material.base_color = material.base_color * albedo;
+#ifdef VERTEX_COLORS
+    // Vertex colours hold the voxel ambient occlusion, which only darkens
+    // ambient light.
+    pbr_input.occlusion = in.color.rgb;
+#endif
+    pbr_input.material.metallic = material.metallic;
+    pbr_input.material.perceptual_roughness = material.perceptual_roughness * roughness;
+    pbr_input.frag_coord = in.position;
+    pbr_input.world_position = in.world_position;
+    pbr_input.world_normal = pbr_functions::prepare_world_normal(in.world_normal, false, is_front);
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.N = N;
+    pbr_input.V = pbr_functions::calculate_view(in.world_position, pbr_input.is_orthographic);
+    pbr_input.flags = mesh.flags;
+
+    var output_color = pbr_functions::pbr(pbr_input);
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color, view.color_grading);
+#endif
+    return output_color;
+}
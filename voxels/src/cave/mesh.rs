@@ -4,132 +4,318 @@ use bevy::{
         mesh::{Indices, VertexAttributeValues},
         render_resource::PrimitiveTopology,
     },
-    tasks::{AsyncComputeTaskPool, Task},
+    utils::HashMap,
 };
-use block_mesh::{greedy_quads, ndshape::Shape, GreedyQuadsBuffer, RIGHT_HANDED_Y_UP_CONFIG};
-use futures_lite::future;
+use block_mesh::{
+    greedy_quads_with_merge_strategy, ndshape::Shape, FaceStrides, GreedyQuadsBuffer,
+    MergeStrategy, OrientedBlockFace, UnorientedQuad, Voxel, VoxelVisibility,
+    RIGHT_HANDED_Y_UP_CONFIG,
+};
+
+use super::{
+    chunk::{CaveChunk, CaveMeshMode},
+    error::CaveChunkError,
+    schedule::CaveChunkTimings,
+    surface_nets::surface_nets,
+    voxelize::{CaveChunkVoxels, CaveVoxel},
+};
+
+pub type Submeshes = Vec<(u8, Mesh)>;
+
+/// Meshes a voxelized chunk, one submesh per palette id present in it.
+pub fn mesh(
+    cave_chunk: &CaveChunk,
+    cave_chunk_voxels: &CaveChunkVoxels,
+) -> Result<Submeshes, CaveChunkError> {
+    let locked = cave_chunk_voxels.data.try_read()?;
+    let voxels = if let Some(voxels) = &*locked {
+        voxels
+    } else {
+        return Ok(Vec::new());
+    };
+
+    let submeshes = match cave_chunk.settings.mesh_mode {
+        CaveMeshMode::Blocky => blocky(voxels, cave_chunk_voxels),
+        CaveMeshMode::Smooth => {
+            let samples = cave_chunk.samples.try_read()?;
+            let (noise_samples, materials) =
+                samples.expanded(&cave_chunk.settings, cave_chunk.sample_len());
+            surface_nets(
+                &cave_chunk.settings,
+                &noise_samples,
+                &materials,
+                2_u32.pow(cave_chunk.subdivisions),
+                cave_chunk.seams,
+            )
+        }
+    };
+
+    Ok(submeshes
+        .into_iter()
+        .map(|(id, submesh)| (id, submesh.build()))
+        .collect())
+}
 
-use super::voxelize::{CaveChunkVoxelizedEvent, CaveChunkVoxels};
+/// Ambient light reaching a vertex by the number of voxels occluding it.
+const AO_CURVE: [f32; 4] = [1.0, 0.75, 0.55, 0.4];
+
+fn blocky(
+    voxels: &[CaveVoxel],
+    cave_chunk_voxels: &CaveChunkVoxels,
+) -> HashMap<u8, SubmeshBuilder> {
+    let shape = &cave_chunk_voxels.shape;
+    let length = shape.as_array()[0];
+    let faces = RIGHT_HANDED_Y_UP_CONFIG.faces;
+    let empty = |p: IVec3| {
+        p.cmpge(IVec3::ZERO).all()
+            && p.cmplt(IVec3::splat(length as i32)).all()
+            && voxels[shape.linearize(p.as_uvec3().to_array()) as usize] == CaveVoxel::EMPTY
+    };
+
+    // Occlusion in every direction, worked out only for faces that show.
+    let occluded: Vec<OccludedVoxel> = voxels
+        .iter()
+        .enumerate()
+        .map(|(index, voxel)| {
+            let mut ao = [[0; 4]; 6];
+            if *voxel != CaveVoxel::EMPTY {
+                let quad = UnorientedQuad {
+                    minimum: shape.delinearize(index as u32),
+                    width: 1,
+                    height: 1,
+                };
+                let position = UVec3::from_array(quad.minimum).as_ivec3();
+                for (face, ao) in faces.iter().zip(&mut ao) {
+                    let normal = IVec3::from_array(face.signed_normal().to_array());
+                    if empty(position + normal) {
+                        *ao = face_ao(voxels, cave_chunk_voxels, face, &quad);
+                    }
+                }
+            }
+            OccludedVoxel { voxel: *voxel, ao }
+        })
+        .collect();
 
-pub struct MeshCaveChunkPlugin;
+    let mut buffer = GreedyQuadsBuffer::new(voxels.len());
+    greedy_quads_with_merge_strategy::<_, _, OcclusionMerger>(
+        &occluded,
+        shape,
+        [0; 3],
+        [length - 1; 3],
+        &faces,
+        &mut buffer,
+    );
 
-impl Plugin for MeshCaveChunkPlugin {
-    fn build(&self, app: &mut App) {
-        app.add_systems(Update, mesh_cave_chunk_voxels);
-        app.add_event::<CaveChunkVoxelsMeshedEvent>();
-        app.add_systems(Update, handle_mesh_cave_chunk_voxels_tasks);
+    let mut submeshes: HashMap<u8, SubmeshBuilder> = HashMap::default();
+    for (face, quads) in faces.iter().zip(buffer.quads.groups.iter()) {
+        for quad in quads {
+            let voxel = voxels[shape.linearize(quad.minimum) as usize];
+            let ao = face_ao(voxels, cave_chunk_voxels, face, quad);
+            let submesh = submeshes.entry(voxel.0).or_default();
+
+            let start = submesh.positions.len() as u32;
+            let mut indices = face.quad_mesh_indices(start);
+            // Split along the more occluded diagonal, so occlusion spreads
+            // evenly over both triangles instead of creasing.
+            if ao[0] + ao[3] > ao[1] + ao[2] {
+                indices = if indices[1] == start + 1 {
+                    [start, start + 1, start + 3, start, start + 3, start + 2]
+                } else {
+                    [start, start + 3, start + 1, start, start + 2, start + 3]
+                };
+            }
+            submesh.indices.extend_from_slice(&indices);
+            submesh
+                .positions
+                .extend_from_slice(&face.quad_mesh_positions(quad, 1.0));
+            submesh.normals.extend_from_slice(&face.quad_mesh_normals());
+            submesh.colors.extend(ao.map(|ao| {
+                let brightness = AO_CURVE[ao as usize];
+                [brightness, brightness, brightness, 1.0]
+            }));
+        }
     }
+    submeshes
 }
 
-fn mesh_cave_chunk_voxels(
-    mut commands: Commands,
-    mut events: EventReader<CaveChunkVoxelizedEvent>,
-) {
-    let task_pool = AsyncComputeTaskPool::get();
-    events.iter().for_each(|ev| {
-        commands
-            .spawn_empty()
-            .insert(spawn_mesh_cave_chunk_voxels_task(
-                task_pool,
-                ev.entity,
-                ev.voxels.clone(),
-            ));
+/// Occlusion of each corner of a quad, in `OrientedBlockFace::quad_corners`
+/// order, from the voxels around it in the layer the face looks into.
+fn face_ao(
+    voxels: &[CaveVoxel],
+    cave_chunk_voxels: &CaveChunkVoxels,
+    face: &OrientedBlockFace,
+    quad: &UnorientedQuad,
+) -> [u8; 4] {
+    let shape = &cave_chunk_voxels.shape;
+    let length = shape.as_array()[0] as i32;
+    let solid = |p: IVec3| {
+        p.cmpge(IVec3::ZERO).all()
+            && p.cmplt(IVec3::splat(length)).all()
+            && voxels[shape.linearize(p.as_uvec3().to_array()) as usize] != CaveVoxel::EMPTY
+    };
+
+    let corners = face.quad_corners(&UnorientedQuad {
+        minimum: quad.minimum,
+        width: 1,
+        height: 1,
+    });
+    // block-mesh has its own glam, so go through arrays.
+    let u = IVec3::from_array((corners[1] - corners[0]).as_ivec3().to_array());
+    let v = IVec3::from_array((corners[2] - corners[0]).as_ivec3().to_array());
+    let layer = UVec3::from_array(quad.minimum).as_ivec3()
+        + IVec3::from_array(face.signed_normal().to_array());
+    let (width, height) = (quad.width as i32 - 1, quad.height as i32 - 1);
+
+    [(false, false), (true, false), (false, true), (true, true)].map(|(max_u, max_v)| {
+        let (du, dv) = (if max_u { u } else { -u }, if max_v { v } else { -v });
+        let own = layer + u * width * max_u as i32 + v * height * max_v as i32;
+        let (side_u, side_v) = (solid(own + du), solid(own + dv));
+        if side_u && side_v {
+            3
+        } else {
+            side_u as u8 + side_v as u8 + solid(own + du + dv) as u8
+        }
     })
 }
 
-#[derive(Component, Deref, DerefMut)]
-// struct MeshCaveChunkVoxelsTask(Task<Option<(Entity, CaveChunkVoxels, Mesh)>>);
-struct MeshCaveChunkVoxelsTask(Task<Option<(Entity, Option<Mesh>)>>);
-
-fn spawn_mesh_cave_chunk_voxels_task(
-    task_pool: &AsyncComputeTaskPool,
-    cave_chunk_entity: Entity,
-    cave_chunk_voxels: CaveChunkVoxels,
-) -> MeshCaveChunkVoxelsTask {
-    MeshCaveChunkVoxelsTask(task_pool.spawn(async move {
-        let locked = cave_chunk_voxels.data.try_read().ok()?;
-        let voxels = if let Some(voxels) = &*locked {
-            voxels
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct OccludedVoxel {
+    voxel: CaveVoxel,
+    /// Corner occlusion of each face, in `RIGHT_HANDED_Y_UP_CONFIG.faces`
+    /// order.
+    ao: [[u8; 4]; 6],
+}
+
+impl Voxel for OccludedVoxel {
+    #[inline]
+    fn get_visibility(&self) -> VoxelVisibility {
+        self.voxel.get_visibility()
+    }
+}
+
+/// Merges faces of one palette id whose occlusion matches in the direction
+/// being meshed, whatever it is in the others.
+struct OcclusionMerger;
+
+impl OcclusionMerger {
+    /// Index into `RIGHT_HANDED_Y_UP_CONFIG.faces` of the direction being
+    /// meshed, which block-mesh only passes on as strides. Its normal is x
+    /// for stride 1, z for the largest stride and y otherwise.
+    fn face(strides: &FaceStrides) -> usize {
+        let axis = if strides.n_stride == 1 {
+            0
+        } else if strides.n_stride > strides.u_stride.max(strides.v_stride) {
+            2
         } else {
-            return Some((
-                cave_chunk_entity,
-                // cave_chunk_voxels.clone(),
-                None,
-            ));
+            1
         };
+        axis + 3 * (strides.visibility_offset == strides.n_stride) as usize
+    }
 
-        let mut buffer = GreedyQuadsBuffer::new(voxels.len());
-        let faces = RIGHT_HANDED_Y_UP_CONFIG.faces;
-        greedy_quads(
-            voxels,
-            &cave_chunk_voxels.shape,
-            [0; 3],
-            [cave_chunk_voxels.shape.as_array()[0] - 1; 3],
-            &faces,
-            &mut buffer,
-        );
-
-        let num_indices = buffer.quads.num_quads() * 6;
-        let num_vertices = buffer.quads.num_quads() * 4;
-        let mut indices = Vec::with_capacity(num_indices);
-        let mut positions = Vec::with_capacity(num_vertices);
-        let mut normals = Vec::with_capacity(num_vertices);
-        for (group, face) in buffer.quads.groups.iter().zip(faces.iter()) {
-            for quad in group.iter() {
-                indices.extend_from_slice(&face.quad_mesh_indices(positions.len() as u32));
-                positions.extend_from_slice(&face.quad_mesh_positions(quad, 1.0));
-                normals.extend_from_slice(&face.quad_mesh_normals());
+    /// Faces along a row from `start` that can join a quad of `value`.
+    fn row_width(
+        voxels: &[OccludedVoxel],
+        visited: &[bool],
+        strides: &FaceStrides,
+        face: usize,
+        value: (CaveVoxel, [u8; 4]),
+        start: u32,
+        max_width: u32,
+    ) -> u32 {
+        let mut width = 0;
+        let mut index = start;
+        while width < max_width {
+            let voxel = &voxels[index as usize];
+            let adjacent = &voxels[index.wrapping_add(strides.visibility_offset) as usize];
+            let shows = match adjacent.get_visibility() {
+                VoxelVisibility::Empty => true,
+                VoxelVisibility::Translucent => voxel.get_visibility() == VoxelVisibility::Opaque,
+                VoxelVisibility::Opaque => false,
+            };
+            if voxel.get_visibility() == VoxelVisibility::Empty
+                || visited[index as usize]
+                || !shows
+                || (voxel.voxel, voxel.ao[face]) != value
+            {
+                break;
             }
+            width += 1;
+            index += strides.u_stride;
         }
+        width
+    }
+}
+
+impl MergeStrategy for OcclusionMerger {
+    type Voxel = OccludedVoxel;
+
+    unsafe fn find_quad(
+        min_index: u32,
+        max_width: u32,
+        max_height: u32,
+        strides: &FaceStrides,
+        voxels: &[OccludedVoxel],
+        visited: &[bool],
+    ) -> (u32, u32) {
+        let face = Self::face(strides);
+        let voxel = &voxels[min_index as usize];
+        let value = (voxel.voxel, voxel.ao[face]);
+        let row_width = |start, max_width| {
+            Self::row_width(voxels, visited, strides, face, value, start, max_width)
+        };
 
-        let mut cave_chunk_mesh = Mesh::new(PrimitiveTopology::TriangleList);
-        cave_chunk_mesh.insert_attribute(
+        // As wide as possible, then as tall as that width allows.
+        let width = row_width(min_index, max_width);
+        let mut height = 1;
+        let mut start = min_index.wrapping_add(strides.v_stride);
+        while height < max_height && row_width(start, width) == width {
+            height += 1;
+            start = start.wrapping_add(strides.v_stride);
+        }
+        (width, height)
+    }
+}
+
+#[derive(Default)]
+pub struct SubmeshBuilder {
+    pub indices: Vec<u32>,
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// Multiplied into the base colour by `CaveMaterial`. Vertices
+    /// without a colour are white.
+    pub colors: Vec<[f32; 4]>,
+}
+
+impl SubmeshBuilder {
+    fn build(mut self) -> Mesh {
+        let num_vertices = self.positions.len();
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(
             Mesh::ATTRIBUTE_POSITION,
-            VertexAttributeValues::Float32x3(positions),
+            VertexAttributeValues::Float32x3(self.positions),
         );
-        cave_chunk_mesh.insert_attribute(
+        mesh.insert_attribute(
             Mesh::ATTRIBUTE_NORMAL,
-            VertexAttributeValues::Float32x3(normals),
+            VertexAttributeValues::Float32x3(self.normals),
+        );
+        self.colors.resize(num_vertices, [1.0; 4]);
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_COLOR,
+            VertexAttributeValues::Float32x4(self.colors),
         );
-        cave_chunk_mesh.insert_attribute(
+        mesh.insert_attribute(
             Mesh::ATTRIBUTE_UV_0,
             VertexAttributeValues::Float32x2(vec![[0.0; 2]; num_vertices]),
         );
-        cave_chunk_mesh.set_indices(Some(Indices::U32(indices)));
-
-        Some((
-            cave_chunk_entity,
-            // cave_chunk_voxels.clone(),
-            Some(cave_chunk_mesh),
-        ))
-    }))
+        mesh.set_indices(Some(Indices::U32(self.indices)));
+        mesh
+    }
 }
 
 #[derive(Event)]
 pub struct CaveChunkVoxelsMeshedEvent {
     pub entity: Entity,
-    // pub voxels: CaveChunkVoxels,
-    pub mesh: Option<Handle<Mesh>>,
-}
-
-fn handle_mesh_cave_chunk_voxels_tasks(
-    mut commands: Commands,
-    mut events: EventWriter<CaveChunkVoxelsMeshedEvent>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut query: Query<(Entity, &mut MeshCaveChunkVoxelsTask)>,
-) {
-    query.for_each_mut(|(task_entity, mut cave_chunk_task)| {
-        if let Some(result) = future::block_on(future::poll_once(&mut **cave_chunk_task)) {
-            commands.entity(task_entity).despawn();
-
-            // if let Some((entity, _voxels, mesh)) = result {
-            if let Some((entity, mesh)) = result {
-                events.send(CaveChunkVoxelsMeshedEvent {
-                    entity,
-                    // voxels,
-                    mesh: mesh.map(|m| meshes.add(m)),
-                });
-            }
-        }
-    })
+    /// One mesh per palette id present in the chunk.
+    pub meshes: Vec<(u8, Handle<Mesh>)>,
+    pub timings: CaveChunkTimings,
 }
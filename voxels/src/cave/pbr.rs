@@ -1,41 +1,53 @@
 use bevy::prelude::*;
 
-use super::{chunk::CaveChunk, mesh::CaveChunkVoxelsMeshedEvent, spawn::SpawnedCaveChunks};
+use super::{chunk::CaveChunk, mesh::CaveChunkVoxelsMeshedEvent};
+
+/// Offset of submeshes from their chunk, in voxels. Meshes are built in the
+/// padded voxel grid, which starts one voxel before the chunk origin.
+pub const SUBMESH_OFFSET: Vec3 = Vec3::splat(-1.0);
+
+/// Scales voxel grid units to world units.
+pub fn voxel_transform(cave_chunk: &CaveChunk) -> Transform {
+    let sample_count = 2_u32.pow(cave_chunk.subdivisions);
+    Transform::from_scale(Vec3::splat(cave_chunk.settings.size / sample_count as f32))
+}
 
 pub fn insert_cave_chunk_pbr(
-    mut spawned_cave_chunks: ResMut<SpawnedCaveChunks>,
     mut commands: Commands,
     mut events: EventReader<CaveChunkVoxelsMeshedEvent>,
-    query: Query<&CaveChunk>,
+    query: Query<(&CaveChunk, Option<&Children>)>,
 ) {
     events.iter().for_each(|ev| {
-        if let Ok(cave_chunk) = query.get(ev.entity) {
-            spawned_cave_chunks.processing.remove(&ev.entity);
+        if let Ok((cave_chunk, children)) = query.get(ev.entity) {
+            for child in children.into_iter().flatten() {
+                commands.entity(*child).despawn_recursive();
+            }
 
-            let mesh = if let Some(mesh) = &ev.mesh {
-                mesh
-            } else {
+            if ev.meshes.is_empty() {
                 return;
-            };
-
-            let sample_count = 2_u32.pow(cave_chunk.subdivisions);
-            let voxel_size = cave_chunk.settings.size / sample_count as f32;
-
-            let pbr = commands
-                .spawn(PbrBundle {
-                    mesh: mesh.clone(),
-                    material: cave_chunk.settings.material.clone(),
-                    transform: Transform::from_translation(Vec3::splat(-1.0)),
-                    ..Default::default()
-                })
-                .id();
+            }
 
             let transform = commands
                 .spawn(SpatialBundle {
-                    transform: Transform::from_scale(Vec3::splat(voxel_size)),
+                    transform: voxel_transform(cave_chunk),
                     ..default()
                 })
-                .add_child(pbr)
+                .with_children(|parent| {
+                    for (id, mesh) in &ev.meshes {
+                        let material = if let Some(entry) = cave_chunk.settings.palette_entry(*id) {
+                            entry.material.clone()
+                        } else {
+                            continue;
+                        };
+
+                        parent.spawn(MaterialMeshBundle {
+                            mesh: mesh.clone(),
+                            material,
+                            transform: Transform::from_translation(SUBMESH_OFFSET),
+                            ..Default::default()
+                        });
+                    }
+                })
                 .id();
 
             commands.entity(ev.entity).add_child(transform);
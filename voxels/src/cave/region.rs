@@ -0,0 +1,703 @@
+use std::{
+    fs,
+    hash::Hasher,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    tasks::{IoTaskPool, Task},
+    utils::{HashMap, HashSet},
+};
+use futures_lite::future;
+
+use super::{
+    chunk::{CaveChunk, CaveChunkSettings, UNIFORM_DENSITY},
+    error::CaveChunkError,
+    generator::{CaveGenerator, CaveGenerators, StableHasher},
+    stream::{CaveChunkKey, StreamedCaveChunks},
+};
+
+pub struct CaveRegionPlugin;
+
+impl Plugin for CaveRegionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CaveRegionStore::new("cave_cache"))
+            .init_resource::<PendingFlush>()
+            .add_systems(Update, maintain_cave_regions)
+            .add_systems(Last, flush_cave_regions_on_exit);
+    }
+}
+
+/// Chunks per axis in a region file.
+const REGION_SIZE: i32 = 8;
+const MAGIC: &[u8; 4] = b"CAVR";
+const VERSION: u8 = 4;
+/// Seconds between writes of changed regions, so that bursts of edits and
+/// new chunks go out together.
+const FLUSH_SECONDS: f32 = 2.0;
+
+/// Density is stored relative to `threshold` as an `i8`, saturating at this
+/// distance from it. Only values near the threshold shape the surface, and
+/// the saturated ones compress into long runs, uniform chunks into one.
+const DENSITY_RANGE: f32 = UNIFORM_DENSITY;
+
+/// Region files of chunk samples under `root`, one directory per cave world.
+/// Each region holds `REGION_SIZE`^3 chunks of one lod, at any number of
+/// resolutions, and each chunk is a palette of material names and runs of
+/// (palette index, density).
+///
+/// Regions are kept in memory while chunks in them are streamed in, and
+/// written back in batches by `maintain_cave_regions`.
+#[derive(Resource, Clone)]
+pub struct CaveRegionStore {
+    /// `None` when disabled, so every chunk is generated.
+    root: Option<PathBuf>,
+    /// Only locked to find or add a region, each of which has its own lock.
+    regions: Arc<Mutex<HashMap<RegionKey, Arc<Mutex<Region>>>>>,
+    /// Held for a whole `flush`, so that an older snapshot of a region is
+    /// never written over a newer one.
+    flushing: Arc<Mutex<()>>,
+}
+
+/// The flush `maintain_cave_regions` started on the `IoTaskPool`, if it
+/// hasn't finished yet.
+#[derive(Resource, Default)]
+struct PendingFlush(Option<Task<()>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RegionKey {
+    /// `fingerprint` of the cave world.
+    world: u64,
+    lod: u32,
+    coord: IVec3,
+}
+
+#[derive(Default)]
+struct Region {
+    /// Whether `chunks` has been read from the file yet.
+    read: bool,
+    /// Set when the region is dropped from the store, so anyone still
+    /// holding it looks it up again.
+    evicted: bool,
+    /// Set when the file couldn't be read or moved aside, so it is never
+    /// written over.
+    read_only: bool,
+    chunks: HashMap<ChunkSlot, StoredChunk>,
+    /// Bumped by every change, and compared with `saved` to tell whether
+    /// the file is behind.
+    version: u64,
+    saved: u64,
+}
+
+impl Region {
+    fn is_dirty(&self) -> bool {
+        !self.read_only && self.version != self.saved
+    }
+
+    fn is_edited(&self, index: u16) -> bool {
+        self.chunks
+            .iter()
+            .any(|(slot, stored)| slot.index == index && stored.edited)
+    }
+}
+
+/// One resolution of one chunk in a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ChunkSlot {
+    index: u16,
+    subdivisions: u8,
+}
+
+struct StoredChunk {
+    /// Edited chunks can't be generated again, so they are never replaced
+    /// by generated ones, and are resampled for other resolutions.
+    edited: bool,
+    bytes: Vec<u8>,
+}
+
+impl CaveRegionStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: Some(root.into()),
+            regions: Default::default(),
+            flushing: Default::default(),
+        }
+    }
+
+    /// A store that neither reads nor writes anything.
+    pub fn disabled() -> Self {
+        Self {
+            root: None,
+            regions: Default::default(),
+            flushing: Default::default(),
+        }
+    }
+
+    pub fn load(
+        &self,
+        settings: &CaveChunkSettings,
+        generator: &dyn CaveGenerator,
+        key: CaveChunkKey,
+        subdivisions: u32,
+        seams: u8,
+    ) -> Result<Option<CaveChunk>, CaveChunkError> {
+        let world = fingerprint(settings, generator);
+        let (region_key, index) = if let Some(location) = self.location(world, key) {
+            location
+        } else {
+            return Ok(None);
+        };
+        let samples = self.with_region(region_key, |region| {
+            let slot = ChunkSlot {
+                index,
+                subdivisions: subdivisions as u8,
+            };
+            if let Some(stored) = region.chunks.get(&slot) {
+                return decode_chunk(settings, subdivisions, &stored.bytes);
+            }
+            // An edit at another resolution is better than a generated chunk
+            // without it.
+            let (source, stored) = region
+                .chunks
+                .iter()
+                .filter(|(other, stored)| other.index == index && stored.edited)
+                .max_by_key(|(other, _)| other.subdivisions)?;
+            let source_subdivisions = source.subdivisions as u32;
+            let (noise_samples, materials) =
+                decode_chunk(settings, source_subdivisions, &stored.bytes)?;
+            Some(resample(
+                &noise_samples,
+                &materials,
+                source_subdivisions,
+                subdivisions,
+            ))
+        })?;
+
+        Ok(samples.map(|(noise_samples, materials)| {
+            CaveChunk::from_samples(settings, subdivisions, seams, noise_samples, materials)
+        }))
+    }
+
+    /// Keeps a newly generated chunk's samples in memory, to be written with
+    /// the rest of its region, unless the chunk has been edited at any
+    /// resolution.
+    pub fn save_generated(
+        &self,
+        generator: &dyn CaveGenerator,
+        key: CaveChunkKey,
+        cave_chunk: &CaveChunk,
+    ) -> Result<(), CaveChunkError> {
+        self.save(generator, key, cave_chunk, false)
+    }
+
+    /// Keeps an edited chunk's samples in memory, replacing the chunk at
+    /// every other resolution.
+    pub fn save_edited(
+        &self,
+        generator: &dyn CaveGenerator,
+        key: CaveChunkKey,
+        cave_chunk: &CaveChunk,
+    ) -> Result<(), CaveChunkError> {
+        self.save(generator, key, cave_chunk, true)
+    }
+
+    fn save(
+        &self,
+        generator: &dyn CaveGenerator,
+        key: CaveChunkKey,
+        cave_chunk: &CaveChunk,
+        edited: bool,
+    ) -> Result<(), CaveChunkError> {
+        let settings = &cave_chunk.settings;
+        let world = fingerprint(settings, generator);
+        let (region_key, index) = if let Some(location) = self.location(world, key) {
+            location
+        } else {
+            return Ok(());
+        };
+        let samples = cave_chunk.samples.read()?;
+        let (noise_samples, materials) = samples.expanded(settings, cave_chunk.sample_len());
+        let bytes = encode_chunk(
+            settings,
+            cave_chunk.subdivisions,
+            &noise_samples,
+            &materials,
+        );
+        drop(samples);
+        let slot = ChunkSlot {
+            index,
+            subdivisions: cave_chunk.subdivisions as u8,
+        };
+
+        self.with_region(region_key, |region| {
+            if edited {
+                region.chunks.retain(|other, _| other.index != index);
+            } else if region.is_edited(index) {
+                return;
+            }
+            region.chunks.insert(slot, StoredChunk { edited, bytes });
+            region.version += 1;
+        })
+    }
+
+    /// Writes every region changed since it was last written.
+    pub fn flush(&self) -> Result<(), CaveChunkError> {
+        let _flushing = self.flushing.lock()?;
+        let regions: Vec<_> = self
+            .regions
+            .lock()?
+            .iter()
+            .map(|(key, region)| (*key, region.clone()))
+            .collect();
+
+        for (key, region) in regions {
+            let (bytes, version) = {
+                let region = region.lock()?;
+                if !region.is_dirty() {
+                    continue;
+                }
+                (encode_region(&region.chunks), region.version)
+            };
+            let path = if let Some(path) = self.path(key) {
+                path
+            } else {
+                continue;
+            };
+            // Changes made while writing stay dirty for the next flush.
+            match write_region(&path, &bytes) {
+                Ok(()) => {
+                    let mut region = region.lock()?;
+                    region.saved = region.saved.max(version);
+                }
+                Err(err) => warn!(path = ?path, err = ?err, "failed to write cave region"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the regions of other worlds and of chunks that aren't streamed
+    /// in, unless they still have changes to write or are in use.
+    pub fn evict<'a>(
+        &self,
+        settings: &CaveChunkSettings,
+        generator: &dyn CaveGenerator,
+        keys: impl IntoIterator<Item = &'a CaveChunkKey>,
+    ) -> Result<(), CaveChunkError> {
+        let world = fingerprint(settings, generator);
+        let keep: HashSet<_> = keys
+            .into_iter()
+            .filter_map(|key| Some(self.location(world, *key)?.0))
+            .collect();
+        self.regions.lock()?.retain(|key, region| {
+            if keep.contains(key) {
+                return true;
+            }
+            match region.try_lock() {
+                Ok(mut region) if !region.is_dirty() => {
+                    region.evicted = true;
+                    false
+                }
+                _ => true,
+            }
+        });
+        Ok(())
+    }
+
+    /// Runs `f` on the region, reading it from its file the first time.
+    fn with_region<T>(
+        &self,
+        key: RegionKey,
+        f: impl FnOnce(&mut Region) -> T,
+    ) -> Result<T, CaveChunkError> {
+        loop {
+            let region = self.regions.lock()?.entry(key).or_default().clone();
+            let mut region = region.lock()?;
+            if region.evicted {
+                continue;
+            }
+            if !region.read {
+                region.read = true;
+                if let Some(path) = self.path(key) {
+                    match read_region(&path) {
+                        Ok(chunks) => region.chunks = chunks,
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(err) => {
+                            // Whatever edits it holds are kept for recovery
+                            // rather than written over.
+                            let aside = path.with_extension("region.bad");
+                            warn!(path = ?path, err = ?err, "unreadable cave region, moving it aside");
+                            if let Err(err) = fs::rename(&path, &aside) {
+                                warn!(path = ?path, err = ?err, "failed to move cave region aside, not writing it");
+                                region.read_only = true;
+                            }
+                        }
+                    }
+                }
+            }
+            return Ok(f(&mut region));
+        }
+    }
+
+    fn location(&self, world: u64, key: CaveChunkKey) -> Option<(RegionKey, u16)> {
+        self.root.as_ref()?;
+        let region = IVec3::new(
+            key.coord.x.div_euclid(REGION_SIZE),
+            key.coord.y.div_euclid(REGION_SIZE),
+            key.coord.z.div_euclid(REGION_SIZE),
+        );
+        let local = key.coord - region * REGION_SIZE;
+        let index = local.x + local.y * REGION_SIZE + local.z * REGION_SIZE * REGION_SIZE;
+        let region_key = RegionKey {
+            world,
+            lod: key.lod,
+            coord: region,
+        };
+        Some((region_key, index as u16))
+    }
+
+    fn path(&self, key: RegionKey) -> Option<PathBuf> {
+        Some(
+            self.root
+                .as_ref()?
+                .join(format!("{:016x}", key.world))
+                .join(key.lod.to_string())
+                .join(format!(
+                    "{}.{}.{}.region",
+                    key.coord.x, key.coord.y, key.coord.z
+                )),
+        )
+    }
+}
+
+/// Every few seconds, drops regions out of range and writes changed ones
+/// from the `IoTaskPool`, one batch at a time.
+fn maintain_cave_regions(
+    store: Res<CaveRegionStore>,
+    settings: Res<CaveChunkSettings>,
+    generators: Res<CaveGenerators>,
+    streamed: Res<StreamedCaveChunks>,
+    time: Res<Time>,
+    mut since_flush: Local<f32>,
+    mut pending: ResMut<PendingFlush>,
+) {
+    if let Some(task) = &mut pending.0 {
+        if future::block_on(future::poll_once(task)).is_none() {
+            return;
+        }
+        pending.0 = None;
+    }
+    *since_flush += time.delta_seconds();
+    if *since_flush < FLUSH_SECONDS {
+        return;
+    }
+    *since_flush = 0.0;
+
+    if let Some(generator) = generators.get(&settings.generator) {
+        if let Err(err) = store.evict(&settings, &*generator, streamed.loaded.keys()) {
+            warn!(err = %err, "failed to evict cave regions");
+        }
+    }
+    let store = store.clone();
+    pending.0 = Some(IoTaskPool::get().spawn(async move {
+        if let Err(err) = store.flush() {
+            warn!(err = %err, "failed to flush cave regions");
+        }
+    }));
+}
+
+fn flush_cave_regions_on_exit(
+    store: Res<CaveRegionStore>,
+    mut pending: ResMut<PendingFlush>,
+    mut exits: EventReader<AppExit>,
+) {
+    if exits.iter().next().is_some() {
+        // Whatever changed since the pending flush took its snapshot is
+        // written after it.
+        if let Some(task) = pending.0.take() {
+            future::block_on(task);
+        }
+        if let Err(err) = store.flush() {
+            warn!(err = %err, "failed to flush cave regions");
+        }
+    }
+}
+
+/// Stable hash of the settings and generator that affect generated samples,
+/// so that changing any of them starts a new cave world instead of reading
+/// stale chunks.
+pub fn fingerprint(settings: &CaveChunkSettings, generator: &dyn CaveGenerator) -> u64 {
+    let mut hasher = StableHasher::default();
+    let mut write = |bytes: &[u8]| hasher.write(bytes);
+
+    write(&settings.threshold.to_le_bytes());
+    write(&settings.frequency.to_le_bytes());
+    write(&settings.seed.to_le_bytes());
+    write(&[settings.noise as u8, settings.octaves]);
+    write(&settings.lacunarity.to_le_bytes());
+    write(&settings.gain.to_le_bytes());
+    write(&settings.material_frequency.to_le_bytes());
+    // Strings go after their length, so neighbouring ones can't trade bytes.
+    write(&(settings.generator.len() as u64).to_le_bytes());
+    write(settings.generator.as_bytes());
+    for entry in &settings.palette {
+        write(&(entry.name.len() as u64).to_le_bytes());
+        write(entry.name.as_bytes());
+        write(&entry.weight.to_le_bytes());
+    }
+    for stratum in &settings.strata {
+        write(&stratum.depth.to_le_bytes());
+        write(&stratum.threshold.to_le_bytes());
+        write(&stratum.frequency.to_le_bytes());
+        write(&[stratum.noise as u8]);
+        for material in &stratum.materials {
+            write(&(material.name.len() as u64).to_le_bytes());
+            write(material.name.as_bytes());
+            write(&material.weight.to_le_bytes());
+        }
+    }
+    write(&settings.stratum_blend.to_le_bytes());
+    write(&settings.biome_frequency.to_le_bytes());
+    write(&settings.biome_depth_shift.to_le_bytes());
+    let worms = &settings.worms;
+    for value in [
+        worms.per_cell,
+        worms.cell_size,
+        worms.length,
+        worms.step,
+        worms.width,
+        worms.width_variation,
+        worms.curvature,
+        worms.curvature_frequency,
+        worms.max_pitch,
+        worms.branch_chance,
+    ] {
+        write(&value.to_le_bytes());
+    }
+    write(&worms.branch_depth.to_le_bytes());
+    write(&settings.uniform_margin.to_le_bytes());
+    write(&generator.fingerprint().to_le_bytes());
+    hasher.finish()
+}
+
+fn read_region(path: &Path) -> io::Result<HashMap<ChunkSlot, StoredChunk>> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut reader = Reader(&bytes);
+    if reader.take(4)? != MAGIC || reader.u8()? != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a cave region file",
+        ));
+    }
+
+    let mut chunks = HashMap::default();
+    for _ in 0..reader.u32()? {
+        let slot = ChunkSlot {
+            index: reader.u16()?,
+            subdivisions: reader.u8()?,
+        };
+        let edited = reader.u8()? != 0;
+        let len = reader.u32()? as usize;
+        let bytes = reader.take(len)?.to_vec();
+        chunks.insert(slot, StoredChunk { edited, bytes });
+    }
+    Ok(chunks)
+}
+
+fn encode_region(chunks: &HashMap<ChunkSlot, StoredChunk>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+    for (slot, chunk) in chunks {
+        bytes.extend_from_slice(&slot.index.to_le_bytes());
+        bytes.push(slot.subdivisions);
+        bytes.push(chunk.edited as u8);
+        bytes.extend_from_slice(&(chunk.bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&chunk.bytes);
+    }
+    bytes
+}
+
+fn write_region(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Unique per write, so no other writer, in this process or another, can
+    // rename it in half written.
+    let tmp = path.with_extension(format!(
+        "{}.{}.tmp",
+        process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)
+}
+
+fn encode_chunk(
+    settings: &CaveChunkSettings,
+    subdivisions: u32,
+    noise_samples: &[f32],
+    materials: &[u8],
+) -> Vec<u8> {
+    let mut palette: Vec<u8> = Vec::new();
+    let mut runs: Vec<(u16, u8, i8)> = Vec::new();
+    for (density, id) in noise_samples.iter().zip(materials.iter()) {
+        let index = if let Some(index) = palette.iter().position(|p| p == id) {
+            index
+        } else {
+            palette.push(*id);
+            palette.len() - 1
+        } as u8;
+        // Keep which side of the threshold every sample is on, so cached
+        // chunks voxelize exactly like freshly generated ones.
+        let quantized = ((density - settings.threshold) / DENSITY_RANGE * 127.0)
+            .round()
+            .clamp(-127.0, 127.0) as i8;
+        let density = if *density > settings.threshold {
+            quantized.max(1)
+        } else {
+            quantized.min(0)
+        };
+
+        match runs.last_mut() {
+            Some((len, i, d)) if *i == index && *d == density && *len < u16::MAX => *len += 1,
+            _ => runs.push((1, index, density)),
+        }
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&settings.size.to_le_bytes());
+    bytes.push(subdivisions as u8);
+    bytes.push(palette.len() as u8);
+    for id in palette {
+        let name = settings
+            .palette_entry(id)
+            .map_or(&[][..], |entry| entry.name.as_bytes());
+        let name = &name[..name.len().min(u8::MAX as usize)];
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name);
+    }
+    bytes.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+    for (len, index, density) in runs {
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.push(index);
+        bytes.push(density as u8);
+    }
+    bytes
+}
+
+fn decode_chunk(
+    settings: &CaveChunkSettings,
+    subdivisions: u32,
+    bytes: &[u8],
+) -> Option<(Vec<f32>, Vec<u8>)> {
+    let mut reader = Reader(bytes);
+    if reader.f32().ok()? != settings.size || reader.u8().ok()? as u32 != subdivisions {
+        return None;
+    }
+
+    let mut palette = Vec::new();
+    for _ in 0..reader.u8().ok()? {
+        let len = reader.u8().ok()? as usize;
+        let name = std::str::from_utf8(reader.take(len).ok()?).ok()?;
+        palette.push(settings.palette_id_by_name(name).unwrap_or(0));
+    }
+
+    let sample_count = 2_usize.pow(subdivisions) + 2;
+    let len = sample_count * sample_count * sample_count;
+    let mut noise_samples = Vec::with_capacity(len);
+    let mut materials = Vec::with_capacity(len);
+    for _ in 0..reader.u32().ok()? {
+        let run = reader.u16().ok()? as usize;
+        let id = *palette.get(reader.u8().ok()? as usize)?;
+        let density = settings.threshold + reader.u8().ok()? as i8 as f32 / 127.0 * DENSITY_RANGE;
+        // Checked before growing, so corrupt runs can't ask for gigabytes.
+        if noise_samples.len() + run > len {
+            return None;
+        }
+        noise_samples.resize(noise_samples.len() + run, density);
+        materials.resize(materials.len() + run, id);
+    }
+
+    if noise_samples.len() != len {
+        return None;
+    }
+    Some((noise_samples, materials))
+}
+
+/// Samples a grid with `from` subdivisions where a grid with `to` would be,
+/// density trilinearly and materials from the nearest sample.
+fn resample(noise_samples: &[f32], materials: &[u8], from: u32, to: u32) -> (Vec<f32>, Vec<u8>) {
+    let source_count = 2_usize.pow(from) + 2;
+    let sample_count = 2_usize.pow(to) + 2;
+    let scale = 2_f32.powi(from as i32 - to as i32);
+    let last = (source_count - 1) as f32;
+    let at = |p: UVec3| p.x as usize + (p.y as usize + p.z as usize * source_count) * source_count;
+
+    let len = sample_count * sample_count * sample_count;
+    let mut resampled_noise = Vec::with_capacity(len);
+    let mut resampled_materials = Vec::with_capacity(len);
+    for z in 0..sample_count {
+        for y in 0..sample_count {
+            for x in 0..sample_count {
+                // Sample 1 is at the chunk's origin in both grids.
+                let p = ((Vec3::new(x as f32, y as f32, z as f32) - 1.0) * scale + 1.0)
+                    .clamp(Vec3::ZERO, Vec3::splat(last));
+                let base = p.floor().min(Vec3::splat(last - 1.0));
+                let t = p - base;
+                let base = base.as_uvec3();
+
+                let mut density = 0.0;
+                for corner in 0..8 {
+                    let offset = UVec3::new(corner & 1, corner >> 1 & 1, corner >> 2);
+                    let weight = Vec3::select(offset.cmpeq(UVec3::ONE), t, 1.0 - t);
+                    density += weight.x * weight.y * weight.z * noise_samples[at(base + offset)];
+                }
+                resampled_noise.push(density);
+                resampled_materials.push(materials[at(p.round().as_uvec3())]);
+            }
+        }
+    }
+    (resampled_noise, resampled_materials)
+}
+
+pub(super) struct Reader<'a>(pub(super) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(super) fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(super) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(super) fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(super) fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
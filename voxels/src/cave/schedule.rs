@@ -0,0 +1,491 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    ecs::{entity::Entities, system::SystemParam},
+    prelude::*,
+    render::primitives::{Aabb, Frustum},
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use futures_lite::future;
+
+use crate::player::Player;
+
+use super::{
+    chunk::{CaveChunk, CaveChunkBundle, CaveChunkSettings},
+    error::CaveChunkError,
+    generator::CaveGenerator,
+    mesh::{self, CaveChunkVoxelsMeshedEvent, Submeshes},
+    region::CaveRegionStore,
+    stream::CaveChunkKey,
+    voxelize::{self, CaveChunkVoxels},
+};
+
+pub struct CaveSchedulePlugin;
+
+impl Plugin for CaveSchedulePlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<CaveScheduleSettings>()
+            .register_type::<CaveChunkStage>()
+            .insert_resource(CaveScheduleSettings::default())
+            .insert_resource(CaveChunkScheduler::default())
+            .add_asset::<Mesh>()
+            .add_event::<CaveChunkVoxelsMeshedEvent>()
+            .add_event::<CaveChunkFailed>();
+    }
+}
+
+#[derive(Resource, Reflect, Debug)]
+#[reflect(Resource)]
+pub struct CaveScheduleSettings {
+    /// Chunk jobs allowed to run at once.
+    pub max_jobs: usize,
+    /// Distance multiplier for chunks outside the player's view, so visible
+    /// chunks further away are worked on first.
+    pub hidden_penalty: f32,
+    /// Times a job is queued again after a retryable error before its chunk
+    /// is marked `CaveChunkStage::Failed`.
+    pub max_retries: u32,
+    /// Seconds a failed chunk waits before its job is tried again, doubled
+    /// with every failure.
+    pub failure_backoff: f32,
+    /// Failures after which a chunk is left `CaveChunkStage::Failed` until
+    /// it is streamed out.
+    pub max_failures: u32,
+}
+
+impl Default for CaveScheduleSettings {
+    fn default() -> Self {
+        Self {
+            max_jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            hidden_penalty: 4.0,
+            max_retries: 3,
+            failure_backoff: 1.0,
+            max_failures: 5,
+        }
+    }
+}
+
+/// Where a chunk entity is in the pipeline.
+#[derive(Component, Reflect, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[reflect(Component)]
+pub enum CaveChunkStage {
+    #[default]
+    Generating,
+    Voxelizing,
+    Meshing,
+    Ready,
+    Failed,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct CaveChunkFailed {
+    pub entity: Entity,
+    pub stage: CaveChunkStage,
+    pub error: CaveChunkError,
+}
+
+/// Work queued for a chunk entity. Generating loads or generates the samples
+/// and inserts the `CaveChunk`; both kinds then voxelize and mesh the chunk.
+#[derive(Clone)]
+pub enum CaveChunkJob {
+    Generate {
+        generator: Arc<dyn CaveGenerator>,
+        store: CaveRegionStore,
+        settings: Box<CaveChunkSettings>,
+        key: CaveChunkKey,
+        origin: Vec3,
+        subdivisions: u32,
+        seams: u8,
+    },
+    Remesh,
+}
+
+struct CaveChunkJobOutput {
+    bundle: Option<CaveChunkBundle>,
+    voxels: CaveChunkVoxels,
+    meshes: Submeshes,
+    timings: CaveChunkTimings,
+}
+
+/// Time a job spent in each stage.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaveChunkTimings {
+    /// `None` for remeshes, which keep their samples.
+    pub generate: Option<Duration>,
+    pub voxelize: Duration,
+    pub mesh: Duration,
+}
+
+struct QueuedJob {
+    job: CaveChunkJob,
+    attempts: u32,
+    /// Times the job has failed for good, to back off by.
+    failures: u32,
+}
+
+impl QueuedJob {
+    fn new(job: CaveChunkJob) -> Self {
+        Self {
+            job,
+            attempts: 0,
+            failures: 0,
+        }
+    }
+}
+
+struct FailedJob {
+    queued: QueuedJob,
+    /// `Time::elapsed` to queue the job again at.
+    retry_at: Duration,
+}
+
+struct RunningJob {
+    queued: QueuedJob,
+    /// Updated by the task as it moves through the stages.
+    stage: Arc<Mutex<CaveChunkStage>>,
+    /// Last stage inserted on the chunk entity.
+    reported: Option<CaveChunkStage>,
+    task: Task<Result<CaveChunkJobOutput, CaveChunkError>>,
+}
+
+/// Runs chunk jobs on the `AsyncComputeTaskPool`, at most one per entity and
+/// `CaveScheduleSettings::max_jobs` overall, nearest visible chunks first.
+#[derive(Resource, Default)]
+pub struct CaveChunkScheduler {
+    queued: HashMap<Entity, QueuedJob>,
+    running: HashMap<Entity, RunningJob>,
+    failed: HashMap<Entity, FailedJob>,
+}
+
+impl CaveChunkScheduler {
+    /// Queues a job for the entity, replacing any job still waiting for it.
+    pub fn queue(&mut self, entity: Entity, job: CaveChunkJob) {
+        self.failed.remove(&entity);
+        self.queued.insert(entity, QueuedJob::new(job));
+    }
+
+    /// Queues the entity's `CaveChunk` to be voxelized and meshed again,
+    /// after any job already running for it.
+    pub fn remesh(&mut self, entity: Entity) {
+        self.queued
+            .entry(entity)
+            .or_insert_with(|| QueuedJob::new(CaveChunkJob::Remesh));
+    }
+
+    /// Jobs waiting for a free slot.
+    pub fn queued_len(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Stage of every running job.
+    pub fn running_stages(&self) -> impl Iterator<Item = CaveChunkStage> + '_ {
+        self.running
+            .values()
+            .map(|running| *running.stage.lock().unwrap())
+    }
+
+    /// Chunks waiting to try a failed job again.
+    pub fn failed_len(&self) -> usize {
+        self.failed.len()
+    }
+
+    /// Drops the entity's queued and failed jobs and cancels its running one.
+    pub fn cancel(&mut self, entity: Entity) {
+        self.queued.remove(&entity);
+        self.running.remove(&entity);
+        self.failed.remove(&entity);
+    }
+}
+
+/// Where the results of finished jobs go.
+#[derive(SystemParam)]
+pub struct CaveChunkJobOutputs<'w> {
+    events: EventWriter<'w, CaveChunkVoxelsMeshedEvent>,
+    failed: EventWriter<'w, CaveChunkFailed>,
+    meshes: ResMut<'w, Assets<Mesh>>,
+}
+
+/// Applies the results of finished jobs, retries or reports failed ones, and
+/// drops the jobs of despawned chunks.
+pub fn handle_cave_chunk_jobs(
+    mut scheduler: ResMut<CaveChunkScheduler>,
+    settings: Res<CaveScheduleSettings>,
+    time: Res<Time>,
+    entities: &Entities,
+    mut commands: Commands,
+    outputs: CaveChunkJobOutputs,
+) {
+    let CaveChunkJobOutputs {
+        mut events,
+        mut failed,
+        mut meshes,
+    } = outputs;
+    let scheduler = &mut *scheduler;
+    scheduler
+        .queued
+        .retain(|entity, _| entities.contains(*entity));
+    scheduler
+        .failed
+        .retain(|entity, _| entities.contains(*entity));
+
+    let mut retries = Vec::new();
+    let mut backing_off = Vec::new();
+    scheduler.running.retain(|entity, running| {
+        // Dropping the task cancels it.
+        if !entities.contains(*entity) {
+            return false;
+        }
+
+        let stage = *running.stage.lock().unwrap();
+        let output = if let Some(output) = future::block_on(future::poll_once(&mut running.task)) {
+            output
+        } else {
+            if running.reported != Some(stage) {
+                running.reported = Some(stage);
+                commands.entity(*entity).insert(stage);
+            }
+            return true;
+        };
+
+        match output {
+            Ok(CaveChunkJobOutput {
+                bundle,
+                voxels,
+                meshes: submeshes,
+                timings,
+            }) => {
+                commands
+                    .entity(*entity)
+                    .insert((CaveChunkStage::Ready, voxels));
+                if let Some(bundle) = bundle {
+                    commands.entity(*entity).insert(bundle);
+                }
+                events.send(CaveChunkVoxelsMeshedEvent {
+                    entity: *entity,
+                    meshes: submeshes
+                        .into_iter()
+                        .map(|(id, m)| (id, meshes.add(m)))
+                        .collect(),
+                    timings,
+                });
+            }
+            Err(error)
+                if error.is_retryable() && running.queued.attempts < settings.max_retries =>
+            {
+                debug!(entity = ?entity, stage = ?stage, error = %error, "retrying cave chunk job");
+                retries.push((
+                    *entity,
+                    QueuedJob {
+                        job: running.queued.job.clone(),
+                        attempts: running.queued.attempts + 1,
+                        failures: running.queued.failures,
+                    },
+                ));
+            }
+            Err(error) => {
+                commands.entity(*entity).insert(CaveChunkStage::Failed);
+                failed.send(CaveChunkFailed {
+                    entity: *entity,
+                    stage,
+                    error,
+                });
+                let failures = running.queued.failures + 1;
+                if failures < settings.max_failures {
+                    let backoff = settings.failure_backoff * 2_f32.powi(failures as i32 - 1);
+                    backing_off.push((
+                        *entity,
+                        FailedJob {
+                            queued: QueuedJob {
+                                job: running.queued.job.clone(),
+                                attempts: 0,
+                                failures,
+                            },
+                            retry_at: time.elapsed() + Duration::from_secs_f32(backoff),
+                        },
+                    ));
+                }
+            }
+        }
+        false
+    });
+    scheduler.failed.extend(backing_off);
+
+    let now = time.elapsed();
+    let due: Vec<_> = scheduler
+        .failed
+        .iter()
+        .filter(|(_, failed)| failed.retry_at <= now)
+        .map(|(entity, _)| *entity)
+        .collect();
+    for entity in due {
+        let failed = scheduler.failed.remove(&entity).unwrap();
+        retries.push((entity, failed.queued));
+    }
+
+    for (entity, queued) in retries {
+        // A job queued since, such as a remesh after another edit, replaces
+        // the retry.
+        scheduler.queued.entry(entity).or_insert(queued);
+    }
+}
+
+/// Starts the highest priority queued jobs that fit in the budget.
+pub fn start_cave_chunk_jobs(
+    mut scheduler: ResMut<CaveChunkScheduler>,
+    settings: Res<CaveScheduleSettings>,
+    player: Query<(&GlobalTransform, &Frustum), With<Player>>,
+    cave_chunks: Query<(&CaveChunk, &Transform)>,
+) {
+    let scheduler = &mut *scheduler;
+    let free = settings.max_jobs.saturating_sub(scheduler.running.len());
+    if free == 0 || scheduler.queued.is_empty() {
+        return;
+    }
+
+    let player = player.get_single().ok();
+    let priority = |origin: Vec3, size: f32| {
+        let (transform, frustum) = if let Some(player) = player {
+            player
+        } else {
+            return 0.0;
+        };
+        let max = origin + Vec3::splat(size);
+        let distance = transform
+            .translation()
+            .clamp(origin, max)
+            .distance(transform.translation());
+        let aabb = Aabb::from_min_max(origin, max);
+        if frustum.intersects_obb(&aabb, &Mat4::IDENTITY, true, true) {
+            distance
+        } else {
+            distance * settings.hidden_penalty
+        }
+    };
+
+    let mut ready: Vec<_> = scheduler
+        .queued
+        .iter()
+        .filter(|(entity, _)| !scheduler.running.contains_key(*entity))
+        .filter_map(|(entity, queued)| {
+            let (origin, size) = match &queued.job {
+                CaveChunkJob::Generate {
+                    settings, origin, ..
+                } => (*origin, settings.size),
+                CaveChunkJob::Remesh => {
+                    let (cave_chunk, transform) = cave_chunks.get(*entity).ok()?;
+                    (transform.translation, cave_chunk.settings.size)
+                }
+            };
+            Some((priority(origin, size), *entity))
+        })
+        .collect();
+    ready.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+    let task_pool = AsyncComputeTaskPool::get();
+    for (_, entity) in ready.into_iter().take(free) {
+        let queued = if let Some(queued) = scheduler.queued.remove(&entity) {
+            queued
+        } else {
+            continue;
+        };
+
+        let stage = Arc::new(Mutex::new(CaveChunkStage::Generating));
+        let task = match queued.job.clone() {
+            CaveChunkJob::Generate {
+                generator,
+                store,
+                settings,
+                key,
+                origin,
+                subdivisions,
+                seams,
+            } => {
+                let stage = stage.clone();
+                task_pool.spawn(async move {
+                    let start = Instant::now();
+                    let cave_chunk = if let Some(cave_chunk) =
+                        store.load(&settings, &*generator, key, subdivisions, seams)?
+                    {
+                        cave_chunk
+                    } else {
+                        let cave_chunk = CaveChunk::generate(
+                            &*generator,
+                            &settings,
+                            origin,
+                            subdivisions,
+                            seams,
+                        );
+                        store.save_generated(&*generator, key, &cave_chunk)?;
+                        cave_chunk
+                    };
+                    let generate = start.elapsed();
+                    let (voxels, meshes, timings) = voxelize_and_mesh(&cave_chunk, &stage)?;
+                    Ok(CaveChunkJobOutput {
+                        bundle: Some(CaveChunkBundle::new(
+                            cave_chunk,
+                            Transform::from_translation(origin),
+                        )),
+                        voxels,
+                        meshes,
+                        timings: CaveChunkTimings {
+                            generate: Some(generate),
+                            ..timings
+                        },
+                    })
+                })
+            }
+            CaveChunkJob::Remesh => {
+                let cave_chunk = if let Ok((cave_chunk, _)) = cave_chunks.get(entity) {
+                    cave_chunk.clone()
+                } else {
+                    continue;
+                };
+                let stage = stage.clone();
+                task_pool.spawn(async move {
+                    let (voxels, meshes, timings) = voxelize_and_mesh(&cave_chunk, &stage)?;
+                    Ok(CaveChunkJobOutput {
+                        bundle: None,
+                        voxels,
+                        meshes,
+                        timings,
+                    })
+                })
+            }
+        };
+
+        scheduler.running.insert(
+            entity,
+            RunningJob {
+                queued,
+                stage,
+                reported: None,
+                task,
+            },
+        );
+    }
+}
+
+fn voxelize_and_mesh(
+    cave_chunk: &CaveChunk,
+    stage: &Mutex<CaveChunkStage>,
+) -> Result<(CaveChunkVoxels, Submeshes, CaveChunkTimings), CaveChunkError> {
+    *stage.lock().unwrap() = CaveChunkStage::Voxelizing;
+    let start = Instant::now();
+    let voxels = voxelize::voxelize(cave_chunk)?;
+    let voxelize = start.elapsed();
+
+    *stage.lock().unwrap() = CaveChunkStage::Meshing;
+    let start = Instant::now();
+    let meshes = mesh::mesh(cave_chunk, &voxels)?;
+    let timings = CaveChunkTimings {
+        generate: None,
+        voxelize,
+        mesh: start.elapsed(),
+    };
+    Ok((voxels, meshes, timings))
+}
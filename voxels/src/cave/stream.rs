@@ -0,0 +1,163 @@
+use bevy::{prelude::*, utils::HashMap};
+
+pub struct CaveStreamPlugin;
+
+impl Plugin for CaveStreamPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<CaveStreamSettings>()
+            .insert_resource(CaveStreamSettings::default())
+            .insert_resource(StreamedCaveChunks::default());
+    }
+}
+
+#[derive(Resource, Reflect, Debug, Clone)]
+#[reflect(Resource)]
+pub struct CaveStreamSettings {
+    pub chunk_size: f32,
+    pub max_lod: u32,
+    pub root_radius: i32,
+    /// A chunk is split into its 8 children while the player is closer than
+    /// this many chunk sizes to it.
+    pub subdivide_distance: f32,
+    pub max_subdivisions: u32,
+    pub min_subdivisions: u32,
+    /// Distance up to which chunks get `max_subdivisions`. Every doubling of
+    /// the distance beyond it drops one subdivision.
+    pub subdivision_falloff: f32,
+}
+
+impl Default for CaveStreamSettings {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1.28,
+            max_lod: 10,
+            root_radius: 2,
+            subdivide_distance: 2.0,
+            max_subdivisions: 5,
+            min_subdivisions: 3,
+            subdivision_falloff: 40.0,
+        }
+    }
+}
+
+impl CaveStreamSettings {
+    pub fn lod_size(&self, lod: u32) -> f32 {
+        self.chunk_size * 2_i32.pow(lod) as f32
+    }
+
+    fn subdivisions(&self, distance: f32) -> u32 {
+        let drop = (distance / self.subdivision_falloff).log2().max(0.0) as u32;
+        self.max_subdivisions
+            .saturating_sub(drop)
+            .max(self.min_subdivisions)
+    }
+}
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CaveChunkKey {
+    pub lod: u32,
+    pub coord: IVec3,
+}
+
+impl CaveChunkKey {
+    pub fn origin(&self, settings: &CaveStreamSettings) -> Vec3 {
+        self.coord.as_vec3() * settings.lod_size(self.lod)
+    }
+
+    pub fn distance(&self, settings: &CaveStreamSettings, point: Vec3) -> f32 {
+        let min = self.origin(settings);
+        let max = min + Vec3::splat(settings.lod_size(self.lod));
+        point.distance(point.clamp(min, max))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CaveChunkDetail {
+    pub subdivisions: u32,
+    pub seams: u8,
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct StreamedCaveChunks {
+    pub center: Option<IVec3>,
+    pub wanted: HashMap<CaveChunkKey, CaveChunkDetail>,
+    pub loaded: HashMap<CaveChunkKey, (Entity, CaveChunkDetail)>,
+    /// Chunks still shown at their old detail until the `loaded` one for the
+    /// same key is ready.
+    pub replaced: HashMap<CaveChunkKey, Entity>,
+}
+
+/// Leaves of an octree over the max lod chunks around `center`, split down
+/// towards lod 0 near `center`.
+pub fn chunks_around(
+    settings: &CaveStreamSettings,
+    center: Vec3,
+) -> HashMap<CaveChunkKey, CaveChunkDetail> {
+    let max_size = settings.lod_size(settings.max_lod);
+    let root = (center / max_size).floor().as_ivec3();
+    let radius = settings.root_radius;
+
+    let mut chunks = HashMap::default();
+    for x in -radius..=radius {
+        for y in -radius..=radius {
+            for z in -radius..=radius {
+                let key = CaveChunkKey {
+                    lod: settings.max_lod,
+                    coord: root + IVec3::new(x, y, z),
+                };
+                let origin = key.origin(settings);
+                if origin.y < -max_size || origin.y + max_size > max_size {
+                    continue;
+                }
+                subdivide(settings, center, key, &mut chunks);
+            }
+        }
+    }
+
+    chunks
+        .iter()
+        .map(|(key, subdivisions)| {
+            let mut seams = 0;
+            for (axis, dir) in IVec3::AXES.iter().enumerate() {
+                for (positive, dir) in [-*dir, *dir].into_iter().enumerate() {
+                    let neighbour = CaveChunkKey {
+                        lod: key.lod,
+                        coord: key.coord + dir,
+                    };
+                    if chunks.get(&neighbour) != Some(subdivisions) {
+                        seams |= 1 << (axis * 2 + positive);
+                    }
+                }
+            }
+            (
+                *key,
+                CaveChunkDetail {
+                    subdivisions: *subdivisions,
+                    seams,
+                },
+            )
+        })
+        .collect()
+}
+
+fn subdivide(
+    settings: &CaveStreamSettings,
+    center: Vec3,
+    key: CaveChunkKey,
+    chunks: &mut HashMap<CaveChunkKey, u32>,
+) {
+    let distance = key.distance(settings, center);
+    if key.lod == 0 || distance >= settings.subdivide_distance * settings.lod_size(key.lod) {
+        chunks.insert(key, settings.subdivisions(distance));
+        return;
+    }
+
+    for i in 0..8 {
+        let offset = IVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+        let child = CaveChunkKey {
+            lod: key.lod - 1,
+            coord: key.coord * 2 + offset,
+        };
+        subdivide(settings, center, child, chunks);
+    }
+}
@@ -0,0 +1,209 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{chunk::CaveChunkSettings, mesh::SubmeshBuilder};
+
+const CORNERS: [UVec3; 8] = [
+    UVec3::new(0, 0, 0),
+    UVec3::new(1, 0, 0),
+    UVec3::new(0, 1, 0),
+    UVec3::new(1, 1, 0),
+    UVec3::new(0, 0, 1),
+    UVec3::new(1, 0, 1),
+    UVec3::new(0, 1, 1),
+    UVec3::new(1, 1, 1),
+];
+
+const EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (2, 3),
+    (4, 5),
+    (6, 7),
+    (0, 2),
+    (1, 3),
+    (4, 6),
+    (5, 7),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+/// How far skirts reach into the rock, in voxels. Two covers the gap to a
+/// neighbour one lod coarser.
+const SKIRT_DEPTH: f32 = 2.0;
+
+/// Meshes the `threshold` isosurface of a chunk's density samples, which
+/// include a one sample apron around the `sample_count`^3 samples the chunk
+/// owns. Positions are in the padded voxel grid, so they line up with greedy
+/// quads. Only edges starting at owned samples get quads, so neighbouring
+/// chunks of the same resolution meet without gaps or overlap.
+///
+/// Open edges of the surface on `seams` faces get a skirt that runs out to
+/// the chunk face and down into the rock, hiding cracks against neighbours
+/// of a different resolution.
+pub fn surface_nets(
+    settings: &CaveChunkSettings,
+    noise_samples: &[f32],
+    materials: &[u8],
+    sample_count: u32,
+    seams: u8,
+) -> HashMap<u8, SubmeshBuilder> {
+    let length = sample_count + 2;
+    let index = |p: UVec3| (p.x + p.y * length + p.z * length * length) as usize;
+    let density = |p: UVec3| noise_samples[index(p)] - settings.threshold;
+
+    let cell_count = length - 1;
+    let cell_index = |p: UVec3| (p.x + p.y * cell_count + p.z * cell_count * cell_count) as usize;
+
+    let mut cell_vertices = vec![None; (cell_count * cell_count * cell_count) as usize];
+    for z in 0..cell_count {
+        for y in 0..cell_count {
+            for x in 0..cell_count {
+                let cell = UVec3::new(x, y, z);
+                let d = CORNERS.map(|corner| density(cell + corner));
+                if d.iter().all(|d| *d > 0.0) || d.iter().all(|d| *d <= 0.0) {
+                    continue;
+                }
+
+                let mut sum = Vec3::ZERO;
+                let mut crossings = 0;
+                for (a, b) in EDGES {
+                    if (d[a] > 0.0) == (d[b] > 0.0) {
+                        continue;
+                    }
+                    let t = d[a] / (d[a] - d[b]);
+                    sum += CORNERS[a].as_vec3().lerp(CORNERS[b].as_vec3(), t);
+                    crossings += 1;
+                }
+
+                let gradient = Vec3::new(
+                    d[1] + d[3] + d[5] + d[7] - d[0] - d[2] - d[4] - d[6],
+                    d[2] + d[3] + d[6] + d[7] - d[0] - d[1] - d[4] - d[5],
+                    d[4] + d[5] + d[6] + d[7] - d[0] - d[1] - d[2] - d[3],
+                );
+
+                cell_vertices[cell_index(cell)] = Some((
+                    cell.as_vec3() + sum / crossings as f32,
+                    (-gradient).normalize_or_zero(),
+                ));
+            }
+        }
+    }
+
+    let mut quads = Vec::new();
+    for z in 1..=sample_count {
+        for y in 1..=sample_count {
+            for x in 1..=sample_count {
+                let p = UVec3::new(x, y, z);
+                for axis in 0..3 {
+                    let (a, b, c) = (
+                        UVec3::AXES[axis],
+                        UVec3::AXES[(axis + 1) % 3],
+                        UVec3::AXES[(axis + 2) % 3],
+                    );
+                    let q = p + a;
+                    let solid_p = density(p) > 0.0;
+                    if solid_p == (density(q) > 0.0) {
+                        continue;
+                    }
+
+                    let mut cells = [p - b - c, p - c, p, p - b];
+                    if !solid_p {
+                        cells.reverse();
+                    }
+
+                    let solid = if solid_p { p } else { q };
+                    quads.push((materials[index(solid)], cells));
+                }
+            }
+        }
+    }
+
+    let edge = |u: UVec3, v: UVec3| {
+        let (u, v) = (cell_index(u), cell_index(v));
+        (u.min(v), u.max(v))
+    };
+    let mut edge_counts: HashMap<(usize, usize), u32> = HashMap::default();
+    for (_, cells) in &quads {
+        for i in 0..4 {
+            *edge_counts
+                .entry(edge(cells[i], cells[(i + 1) % 4]))
+                .or_default() += 1;
+        }
+    }
+
+    let mut submeshes: HashMap<u8, (SubmeshBuilder, HashMap<usize, u32>)> = HashMap::default();
+    for (id, cells) in quads {
+        let (submesh, remap) = submeshes.entry(id).or_default();
+
+        let quad = cells.map(|cell| {
+            let i = cell_index(cell);
+            *remap.entry(i).or_insert_with(|| {
+                let (position, normal) = cell_vertices[i].unwrap_or_default();
+                submesh.positions.push(position.to_array());
+                submesh.normals.push(normal.to_array());
+                submesh.positions.len() as u32 - 1
+            })
+        });
+        submesh
+            .indices
+            .extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+
+        for i in 0..4 {
+            let (u, v) = (cells[i], cells[(i + 1) % 4]);
+            if edge_counts[&edge(u, v)] != 1 {
+                continue;
+            }
+            let plane = if let Some(plane) = seam_plane(u, v, cell_count, seams) {
+                plane
+            } else {
+                continue;
+            };
+
+            let base = submesh.positions.len() as u32;
+            for (cell, vertex) in [(v, quad[(i + 1) % 4]), (u, quad[i])] {
+                let (position, normal) = cell_vertices[cell_index(cell)].unwrap_or_default();
+                submesh.positions.push(submesh.positions[vertex as usize]);
+                submesh.normals.push(normal.to_array());
+
+                let mut skirt = position;
+                skirt[plane.0] = plane.1;
+                submesh
+                    .positions
+                    .push((skirt - normal * SKIRT_DEPTH).to_array());
+                submesh.normals.push(normal.to_array());
+            }
+            submesh.indices.extend_from_slice(&[
+                base,
+                base + 2,
+                base + 3,
+                base,
+                base + 3,
+                base + 1,
+            ]);
+        }
+    }
+
+    submeshes
+        .into_iter()
+        .map(|(id, (submesh, _))| (id, submesh))
+        .collect()
+}
+
+/// Axis and padded coordinate of the seam face both cells of an open edge
+/// lie against, if any.
+fn seam_plane(u: UVec3, v: UVec3, cell_count: u32, seams: u8) -> Option<(usize, f32)> {
+    for axis in 0..3 {
+        for positive in [false, true] {
+            if seams & (1 << (axis * 2 + positive as usize)) == 0 {
+                continue;
+            }
+            let layer = if positive { cell_count - 1 } else { 0 };
+            if u[axis] == layer && v[axis] == layer {
+                let plane = if positive { cell_count } else { 1 };
+                return Some((axis, plane as f32));
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,260 @@
+use std::sync::RwLockReadGuard;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use block_mesh::ndshape::Shape;
+
+use crate::player::Player;
+
+use super::{
+    chunk::{CaveChunk, CaveChunkFill},
+    index::CaveChunkIndex,
+    stream::{CaveChunkKey, CaveStreamSettings},
+    voxelize::{CaveChunkVoxels, CaveVoxel},
+};
+
+/// Voxel queries in world space over the voxelized chunks.
+#[derive(SystemParam)]
+pub struct CaveWorld<'w, 's> {
+    index: Res<'w, CaveChunkIndex>,
+    stream_settings: Res<'w, CaveStreamSettings>,
+    cave_chunks: Query<
+        'w,
+        's,
+        (
+            &'static CaveChunk,
+            &'static CaveChunkVoxels,
+            &'static Transform,
+        ),
+        Without<Player>,
+    >,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaveRayHit {
+    pub entity: Entity,
+    /// Voxel in the chunk's grid, from zero at the chunk origin.
+    pub voxel: IVec3,
+    /// Normal of the face the ray entered through, zero if it started inside
+    /// the voxel.
+    pub normal: IVec3,
+    /// `CaveChunkSettings::palette` id.
+    pub material: u8,
+    pub distance: f32,
+    pub position: Vec3,
+}
+
+/// A voxelized chunk with its grid.
+struct VoxelGrid<'a> {
+    entity: Entity,
+    voxels: &'a CaveChunkVoxels,
+    data: RwLockReadGuard<'a, Option<Vec<CaveVoxel>>>,
+    origin: Vec3,
+    voxel_size: f32,
+    sample_count: i32,
+}
+
+impl VoxelGrid<'_> {
+    fn voxel(&self, voxel: IVec3) -> CaveVoxel {
+        match (self.data.as_deref(), self.voxels.fill) {
+            // Chunk voxels start after the padding shared with neighbours.
+            (Some(data), _) => {
+                data[self
+                    .voxels
+                    .shape
+                    .linearize((voxel + 1).as_uvec3().to_array()) as usize]
+            }
+            (None, CaveChunkFill::Solid(material)) => CaveVoxel(material),
+            (None, _) => CaveVoxel::EMPTY,
+        }
+    }
+
+    fn cell(&self, point: Vec3) -> IVec3 {
+        ((point - self.origin) / self.voxel_size)
+            .floor()
+            .as_ivec3()
+            .clamp(IVec3::ZERO, IVec3::splat(self.sample_count - 1))
+    }
+}
+
+impl CaveWorld<'_, '_> {
+    /// The grid of the chunk at `key`, if it is voxelized. Voxels a panicking
+    /// thread left poisoned count as not voxelized.
+    fn grid(&self, key: CaveChunkKey) -> Option<VoxelGrid<'_>> {
+        let entity = self.index.get(key)?.entity;
+        let (cave_chunk, voxels, transform) = self.cave_chunks.get(entity).ok()?;
+        let sample_count = 2_i32.pow(cave_chunk.subdivisions);
+        Some(VoxelGrid {
+            entity,
+            voxels,
+            data: voxels.data.try_read().ok()?,
+            origin: transform.translation,
+            voxel_size: cave_chunk.settings.size / sample_count as f32,
+            sample_count,
+        })
+    }
+
+    fn grid_at(&self, point: Vec3) -> Option<VoxelGrid<'_>> {
+        let (key, _) = self.index.at(&self.stream_settings, point)?;
+        self.grid(key)
+    }
+
+    /// Whether a voxelized chunk contains the point.
+    pub fn is_voxelized(&self, point: Vec3) -> bool {
+        self.grid_at(point).is_some()
+    }
+
+    /// Bounds of the solid voxels touching the box from `min` to `max`.
+    pub fn solids(&self, min: Vec3, max: Vec3) -> Vec<(Vec3, Vec3)> {
+        let mut solids = Vec::new();
+        self.any_solid(min, max, |min, max| {
+            solids.push((min, max));
+            false
+        });
+        solids
+    }
+
+    /// Whether `hit` accepts the bounds of any solid voxel touching the box
+    /// from `min` to `max`, stopping at the first one it does.
+    pub fn any_solid(&self, min: Vec3, max: Vec3, mut hit: impl FnMut(Vec3, Vec3) -> bool) -> bool {
+        for lod in 0..=self.stream_settings.max_lod {
+            let size = self.stream_settings.lod_size(lod);
+            let (from, to) = (
+                (min / size).floor().as_ivec3(),
+                (max / size).floor().as_ivec3(),
+            );
+            for z in from.z..=to.z {
+                for y in from.y..=to.y {
+                    for x in from.x..=to.x {
+                        let grid = if let Some(grid) = self.grid(CaveChunkKey {
+                            lod,
+                            coord: IVec3::new(x, y, z),
+                        }) {
+                            grid
+                        } else {
+                            continue;
+                        };
+                        if grid.data.is_none() && grid.voxels.fill == CaveChunkFill::Empty {
+                            continue;
+                        }
+
+                        let (from, to) = (grid.cell(min), grid.cell(max));
+                        for z in from.z..=to.z {
+                            for y in from.y..=to.y {
+                                for x in from.x..=to.x {
+                                    let voxel = IVec3::new(x, y, z);
+                                    if grid.voxel(voxel) == CaveVoxel::EMPTY {
+                                        continue;
+                                    }
+                                    let min = grid.origin + voxel.as_vec3() * grid.voxel_size;
+                                    if hit(min, min + Vec3::splat(grid.voxel_size)) {
+                                        return true;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// First solid voxel along the ray, stepping through the voxel grid of
+    /// every chunk it passes. Space not voxelized yet is treated as empty.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<CaveRayHit> {
+        let direction = direction.try_normalize()?;
+        let step = direction.signum().as_ivec3();
+        let boundary = |p: f32, d: f32| {
+            if d > 0.0 {
+                p.floor() + 1.0
+            } else {
+                p.ceil() - 1.0
+            }
+        };
+
+        let mut distance = 0.0;
+        let mut normal = IVec3::ZERO;
+        while distance <= max_distance {
+            let lod_size = self.stream_settings.lod_size(0);
+            // Nudge past the boundary just crossed, into the next cell.
+            let nudge = lod_size * 1e-5;
+            let point = origin + direction * (distance + nudge);
+
+            let grid = if let Some(grid) = self.grid_at(point) {
+                grid
+            } else {
+                // Skip the smallest chunk this point could belong to.
+                let p = point / lod_size;
+                let (exit, axis) = axis_min(Vec3::select(
+                    direction.cmpeq(Vec3::ZERO),
+                    Vec3::splat(f32::INFINITY),
+                    (Vec3::new(
+                        boundary(p.x, direction.x),
+                        boundary(p.y, direction.y),
+                        boundary(p.z, direction.z),
+                    ) * lod_size
+                        - origin)
+                        / direction,
+                ));
+                distance = exit.max(distance + nudge);
+                normal = -step * IVec3::AXES[axis];
+                continue;
+            };
+            // Rounding can put the point just outside the grid its chunk key
+            // picked, so always move on by at least the nudge.
+            let start = distance + nudge;
+
+            let local = (point - grid.origin) / grid.voxel_size;
+            let mut cell = grid.cell(point);
+            let delta = grid.voxel_size / direction.abs();
+            let mut next = Vec3::select(
+                direction.cmpeq(Vec3::ZERO),
+                Vec3::splat(f32::INFINITY),
+                distance
+                    + ((cell.as_vec3() + step.max(IVec3::ZERO).as_vec3() - local) / direction)
+                        * grid.voxel_size,
+            );
+
+            loop {
+                let voxel = grid.voxel(cell);
+                if voxel != CaveVoxel::EMPTY {
+                    return Some(CaveRayHit {
+                        entity: grid.entity,
+                        voxel: cell,
+                        normal,
+                        material: voxel.0,
+                        distance,
+                        position: origin + direction * distance,
+                    });
+                }
+
+                let (t, axis) = axis_min(next);
+                if t > max_distance {
+                    return None;
+                }
+                distance = t.max(start);
+                normal = -step * IVec3::AXES[axis];
+                cell[axis] += step[axis];
+                next[axis] += delta[axis];
+                if cell[axis] < 0 || cell[axis] >= grid.sample_count {
+                    break;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Smallest component and its axis.
+fn axis_min(v: Vec3) -> (f32, usize) {
+    (0..3).fold(
+        (v.x, 0),
+        |(min, axis), i| {
+            if v[i] < min {
+                (v[i], i)
+            } else {
+                (min, axis)
+            }
+        },
+    )
+}
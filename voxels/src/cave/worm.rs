@@ -0,0 +1,253 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::prelude::*;
+
+use super::{chunk::CaveChunkSettings, generator::CaveSampleGrid};
+
+/// Density change per voxel of distance from a tunnel wall.
+const WALL_SLOPE: f32 = 0.05;
+/// Voxels outside a tunnel wall still pulled towards it, so smooth meshes
+/// see the same slope on both sides of the wall.
+const WALL_VOXELS: f32 = 2.0;
+
+/// Tunnels carved along meandering paths through the generated density.
+#[derive(Reflect, Debug, Clone)]
+pub struct CaveWormSettings {
+    /// Expected worms starting in each cell, 0 disabling them.
+    pub per_cell: f32,
+    /// Side of the cubes worms start in.
+    pub cell_size: f32,
+    pub length: f32,
+    /// Distance between the points of a worm's path.
+    pub step: f32,
+    /// Tunnel diameter.
+    pub width: f32,
+    /// Fraction of `width` the diameter varies by along a worm.
+    pub width_variation: f32,
+    /// Sharpest turn, in radians per unit travelled.
+    pub curvature: f32,
+    /// How often turns change direction, per unit travelled.
+    pub curvature_frequency: f32,
+    /// Steepest slope in radians, so tunnels stay walkable.
+    pub max_pitch: f32,
+    /// Chance of a worm forking into a branch half as long.
+    pub branch_chance: f32,
+    /// Most forks between a worm and its furthest branch.
+    pub branch_depth: u32,
+}
+
+impl Default for CaveWormSettings {
+    fn default() -> Self {
+        Self {
+            per_cell: 0.5,
+            cell_size: 48.0,
+            length: 96.0,
+            step: 1.0,
+            width: 3.0,
+            width_variation: 0.3,
+            curvature: 0.08,
+            curvature_frequency: 0.05,
+            max_pitch: 0.4,
+            branch_chance: 0.4,
+            branch_depth: 2,
+        }
+    }
+}
+
+/// Centre line of a tunnel, with its radius at every point.
+#[derive(Debug, Clone)]
+pub struct CaveWorm {
+    pub points: Vec<(Vec3, f32)>,
+}
+
+impl CaveWorm {
+    fn touches(&self, min: Vec3, max: Vec3) -> bool {
+        let (lo, hi) = self.points.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(lo, hi), (point, radius)| (lo.min(*point - *radius), hi.max(*point + *radius)),
+        );
+        lo.cmple(max).all() && hi.cmpge(min).all()
+    }
+}
+
+impl CaveWormSettings {
+    /// Furthest a worm's branches and walls get from where it starts.
+    fn reach(&self) -> f32 {
+        let branches: f32 = (1..=self.branch_depth)
+            .map(|depth| 0.5_f32.powi(depth as i32))
+            .sum();
+        self.length * (1.0 + branches) + self.width * (1.0 + self.width_variation)
+    }
+
+    /// Worms and branches passing through the box from `min` to `max`. Worms
+    /// only depend on the seed and the cell they start in, so every box
+    /// they cross sees the same path.
+    pub fn worms(&self, seed: i32, min: Vec3, max: Vec3) -> Vec<CaveWorm> {
+        let mut worms = Vec::new();
+        if self.per_cell <= 0.0 || self.cell_size <= 0.0 || self.step <= 0.0 {
+            return worms;
+        }
+
+        let reach = self.reach();
+        let from = ((min - reach) / self.cell_size).floor().as_ivec3();
+        let to = ((max + reach) / self.cell_size).floor().as_ivec3();
+        for z in from.z..=to.z {
+            for y in from.y..=to.y {
+                for x in from.x..=to.x {
+                    let cell = hash(&[seed as u32, x as u32, y as u32, z as u32]);
+                    let count =
+                        self.per_cell as u32 + u32::from(random(cell, 0) < self.per_cell.fract());
+                    for i in 0..count {
+                        let key = hash(&[cell, i]);
+                        let start = (IVec3::new(x, y, z).as_vec3()
+                            + Vec3::new(random(key, 0), random(key, 1), random(key, 2)))
+                            * self.cell_size;
+                        if start.clamp(min, max).distance(start) > reach {
+                            continue;
+                        }
+                        let heading = Vec2::new(
+                            random(key, 3) * TAU,
+                            (random(key, 4) * 2.0 - 1.0) * self.max_pitch,
+                        );
+                        self.grow(key, start, heading, self.length, 0, &mut worms);
+                    }
+                }
+            }
+        }
+        worms.retain(|worm| worm.touches(min, max));
+        worms
+    }
+
+    /// Adds the path from `start` along `heading`, a yaw and a pitch, and
+    /// its branches.
+    fn grow(
+        &self,
+        key: u32,
+        start: Vec3,
+        heading: Vec2,
+        length: f32,
+        depth: u32,
+        worms: &mut Vec<CaveWorm>,
+    ) {
+        let steps = ((length / self.step).ceil() as usize).max(1);
+        let mut points = Vec::with_capacity(steps + 1);
+        let (mut yaw, mut pitch) = (heading.x, heading.y);
+        let mut position = start;
+        for i in 0..=steps {
+            let t = i as f32 * self.step * self.curvature_frequency;
+            let radius = self.width / 2.0 * (1.0 + self.width_variation * perlin(key, 0, t));
+            points.push((position, radius));
+
+            yaw += perlin(key, 1, t) * self.curvature * self.step;
+            pitch = (pitch + perlin(key, 2, t) * self.curvature * self.step)
+                .clamp(-self.max_pitch, self.max_pitch);
+            position += Vec3::new(
+                yaw.cos() * pitch.cos(),
+                pitch.sin(),
+                yaw.sin() * pitch.cos(),
+            ) * self.step;
+        }
+
+        if depth < self.branch_depth && random(key, 5) < self.branch_chance {
+            // Fork somewhere in the middle half, turning off to one side.
+            let at = steps / 4 + (random(key, 6) * (steps / 2) as f32) as usize;
+            let direction = (points[at + 1].0 - points[at].0).normalize_or_zero();
+            let turn = (0.25 + 0.25 * random(key, 7)) * PI;
+            let turn = if random(key, 8) < 0.5 { -turn } else { turn };
+            self.grow(
+                hash(&[key, depth]),
+                points[at].0,
+                Vec2::new(
+                    direction.z.atan2(direction.x) + turn,
+                    direction.y.clamp(-1.0, 1.0).asin(),
+                ),
+                length / 2.0,
+                depth + 1,
+                worms,
+            );
+        }
+        worms.push(CaveWorm { points });
+    }
+}
+
+/// Lowers the density along `worms`, from `worms_near` the grid, below the
+/// threshold, leaving samples outside the tunnels alone. Tunnels are at least
+/// a voxel wide, so grids with voxels wider than a tunnel still open it.
+pub fn carve_worms(
+    settings: &CaveChunkSettings,
+    grid: &CaveSampleGrid,
+    worms: &[CaveWorm],
+    density: &mut [f32],
+) {
+    let margin = WALL_VOXELS * grid.voxel_size;
+    let length = grid.length as i32;
+    let cell = |point: Vec3| (point - grid.origin) / grid.voxel_size + 1.0;
+
+    for worm in worms {
+        for segment in worm.points.windows(2) {
+            let ((a, a_radius), (b, b_radius)) = (segment[0], segment[1]);
+            let (a_radius, b_radius) =
+                (a_radius.max(grid.voxel_size), b_radius.max(grid.voxel_size));
+            let reach = a_radius.max(b_radius) + margin;
+            let from = cell(a.min(b) - reach).floor().as_ivec3().max(IVec3::ZERO);
+            let to = cell(a.max(b) + reach)
+                .ceil()
+                .as_ivec3()
+                .min(IVec3::splat(length - 1));
+
+            let ab = b - a;
+            for z in from.z..=to.z {
+                for y in from.y..=to.y {
+                    for x in from.x..=to.x {
+                        let i = (x + y * length + z * length * length) as usize;
+                        let p = grid.position(i);
+                        let t = ((p - a).dot(ab) / ab.length_squared().max(f32::EPSILON))
+                            .clamp(0.0, 1.0);
+                        let distance =
+                            p.distance(a + ab * t) - (a_radius + (b_radius - a_radius) * t);
+                        if distance < margin {
+                            density[i] = density[i]
+                                .min(settings.threshold + distance / grid.voxel_size * WALL_SLOPE);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Worms `carve_worms` might change samples of the grid along.
+pub fn worms_near(settings: &CaveChunkSettings, grid: &CaveSampleGrid) -> Vec<CaveWorm> {
+    if grid.is_empty() {
+        return Vec::new();
+    }
+    let margin = WALL_VOXELS * grid.voxel_size;
+    let (min, max) = (grid.position(0), grid.position(grid.len() - 1));
+    settings
+        .worms
+        .worms(settings.seed, min - margin, max + margin)
+}
+
+fn hash(values: &[u32]) -> u32 {
+    values.iter().fold(0x811c_9dc5, |h, value| {
+        let mut h = (h ^ value).wrapping_mul(0x5bd1_e995);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x8da6_b343);
+        h ^ (h >> 13)
+    })
+}
+
+/// Uniform in [0, 1), a different one for every `channel` of a key.
+fn random(key: u32, channel: u32) -> f32 {
+    (hash(&[key, channel]) >> 8) as f32 / (1 << 24) as f32
+}
+
+/// One dimensional gradient noise in about [-1, 1].
+fn perlin(key: u32, channel: u32, t: f32) -> f32 {
+    let i = t.floor();
+    let f = t - i;
+    let gradient = |i: f32| random(hash(&[key, channel]), i as i32 as u32) * 2.0 - 1.0;
+    let (a, b) = (gradient(i) * f, gradient(i + 1.0) * (f - 1.0));
+    let s = f * f * (3.0 - 2.0 * f);
+    (a + (b - a) * s) * 2.0
+}
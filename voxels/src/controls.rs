@@ -0,0 +1,312 @@
+use std::{fs, io, path::Path};
+
+use bevy::{
+    ecs::system::SystemParam,
+    input::{mouse::MouseMotion, InputSystem},
+    prelude::*,
+    utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::inspector::InspectorState;
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        // Controls inserted beforehand, such as by tests, are kept.
+        if !app.world.contains_resource::<Controls>() {
+            app.insert_resource(Controls::load(CONTROLS_PATH));
+        }
+        app.register_type::<Action>()
+            .init_resource::<ActionState>()
+            .add_systems(PreUpdate, update_actions.after(InputSystem));
+    }
+}
+
+/// Bindings file read at startup, relative to the working directory.
+pub const CONTROLS_PATH: &str = "controls.ron";
+
+/// Written above the bindings in generated controls files.
+const CONTROLS_HEADER: &str = "\
+// Bindings read from the working directory at startup. Every action can have
+// any number of bindings; delete this file to go back to the defaults.
+";
+
+/// Values above this count as pressed.
+const PRESS_THRESHOLD: f32 = 0.5;
+
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    LookUp,
+    LookDown,
+    LookLeft,
+    LookRight,
+    Sprint,
+    /// Switches between flying and walking.
+    ToggleWalk,
+    Dig,
+    Fill,
+    /// Shows the inspector and releases the cursor, or hides it and grabs
+    /// the cursor.
+    ToggleInspector,
+    /// Switches to the next `CameraMode`.
+    CycleCamera,
+    /// Adds the current view to the end of the camera path.
+    RecordKeyframe,
+    ClearKeyframes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MouseAxis {
+    X,
+    Y,
+}
+
+/// Half of an axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AxisSign {
+    Positive,
+    Negative,
+}
+
+impl AxisSign {
+    fn half(self, value: f32) -> f32 {
+        match self {
+            AxisSign::Positive => value.max(0.0),
+            AxisSign::Negative => (-value).max(0.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Mouse movement in pixels, read by `ActionState::motion` rather than
+    /// `ActionState::value`.
+    MouseMotion(MouseAxis, AxisSign),
+    GamepadButton(GamepadButtonType),
+    GamepadAxis(GamepadAxisType, AxisSign),
+}
+
+/// Actions and what triggers them, any number of bindings per action.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Controls {
+    /// Gamepad axis values nearer zero than this are ignored.
+    pub deadzone: f32,
+    pub bindings: Vec<(Action, Binding)>,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        use self::{Action::*, AxisSign::*, Binding::*, GamepadAxisType::*, GamepadButtonType::*};
+        Self {
+            deadzone: 0.15,
+            bindings: vec![
+                (MoveForward, Key(KeyCode::W)),
+                (MoveBack, Key(KeyCode::S)),
+                (MoveLeft, Key(KeyCode::A)),
+                (MoveRight, Key(KeyCode::D)),
+                (MoveUp, Key(KeyCode::Space)),
+                (MoveDown, Key(KeyCode::ControlLeft)),
+                (LookUp, Key(KeyCode::Up)),
+                (LookDown, Key(KeyCode::Down)),
+                (LookLeft, Key(KeyCode::Left)),
+                (LookRight, Key(KeyCode::Right)),
+                (Sprint, Key(KeyCode::ShiftLeft)),
+                (ToggleWalk, Key(KeyCode::F)),
+                (ToggleInspector, Key(KeyCode::Escape)),
+                (CycleCamera, Key(KeyCode::C)),
+                (RecordKeyframe, Key(KeyCode::K)),
+                (ClearKeyframes, Key(KeyCode::Back)),
+                (LookUp, MouseMotion(MouseAxis::Y, Negative)),
+                (LookDown, MouseMotion(MouseAxis::Y, Positive)),
+                (LookLeft, MouseMotion(MouseAxis::X, Negative)),
+                (LookRight, MouseMotion(MouseAxis::X, Positive)),
+                (Dig, Mouse(MouseButton::Left)),
+                (Fill, Mouse(MouseButton::Right)),
+                (MoveForward, GamepadAxis(LeftStickY, Positive)),
+                (MoveBack, GamepadAxis(LeftStickY, Negative)),
+                (MoveLeft, GamepadAxis(LeftStickX, Negative)),
+                (MoveRight, GamepadAxis(LeftStickX, Positive)),
+                (MoveUp, GamepadButton(South)),
+                (MoveDown, GamepadButton(East)),
+                (LookUp, GamepadAxis(RightStickY, Positive)),
+                (LookDown, GamepadAxis(RightStickY, Negative)),
+                (LookLeft, GamepadAxis(RightStickX, Negative)),
+                (LookRight, GamepadAxis(RightStickX, Positive)),
+                (Sprint, GamepadButton(LeftThumb)),
+                (ToggleWalk, GamepadButton(North)),
+                (Dig, GamepadButton(RightTrigger2)),
+                (Fill, GamepadButton(LeftTrigger2)),
+                (ToggleInspector, GamepadButton(Start)),
+                (CycleCamera, GamepadButton(Select)),
+                (RecordKeyframe, GamepadButton(DPadUp)),
+                (ClearKeyframes, GamepadButton(DPadDown)),
+            ],
+        }
+    }
+}
+
+impl Controls {
+    /// Reads bindings from a RON file, falling back to the defaults if it
+    /// can't be read. A missing file is created from the defaults, so there
+    /// is one to edit.
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|err| {
+                warn!(path = ?path, err = %err, "invalid controls, using the defaults");
+                Self::default()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let controls = Self::default();
+                if let Err(err) = controls.save(path) {
+                    warn!(path = ?path, err = ?err, "failed to write the default controls");
+                }
+                controls
+            }
+            Err(err) => {
+                warn!(path = ?path, err = ?err, "failed to read controls, using the defaults");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        // Keeps each binding on one line.
+        let config = ron::ser::PrettyConfig::new().depth_limit(2);
+        let text = ron::ser::to_string_pretty(self, config)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(path, format!("{CONTROLS_HEADER}{text}\n"))
+    }
+}
+
+/// Where every action is this frame.
+#[derive(Resource, Default, Debug)]
+pub struct ActionState {
+    values: HashMap<Action, f32>,
+    previous: HashMap<Action, f32>,
+    /// Mouse movement is a distance rather than a strength, so it is kept
+    /// apart.
+    motion: HashMap<Action, f32>,
+}
+
+impl ActionState {
+    /// Strongest of the buttons and gamepad axes bound to the action, from 0
+    /// to 1.
+    pub fn value(&self, action: Action) -> f32 {
+        self.values.get(&action).copied().unwrap_or_default()
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.value(action) > PRESS_THRESHOLD
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.pressed(action)
+            && self.previous.get(&action).copied().unwrap_or_default() <= PRESS_THRESHOLD
+    }
+
+    /// `value` of `positive` less that of `negative`.
+    pub fn axis(&self, negative: Action, positive: Action) -> f32 {
+        self.value(positive) - self.value(negative)
+    }
+
+    /// Pixels the mouse moved this frame along the action's bindings.
+    pub fn motion(&self, action: Action) -> f32 {
+        self.motion.get(&action).copied().unwrap_or_default()
+    }
+
+    /// `motion` of `positive` less that of `negative`.
+    pub fn motion_axis(&self, negative: Action, positive: Action) -> f32 {
+        self.motion(positive) - self.motion(negative)
+    }
+}
+
+/// Input resources, missing in apps without the devices' plugins.
+#[derive(SystemParam)]
+struct InputSources<'w, 's> {
+    keys: Option<Res<'w, Input<KeyCode>>>,
+    mouse_buttons: Option<Res<'w, Input<MouseButton>>>,
+    mouse_motion: EventReader<'w, 's, MouseMotion>,
+    gamepads: Option<Res<'w, Gamepads>>,
+    gamepad_buttons: Option<Res<'w, Input<GamepadButton>>>,
+    gamepad_axes: Option<Res<'w, Axis<GamepadAxis>>>,
+    /// While the inspector is up the mouse belongs to its windows.
+    inspector: Option<Res<'w, State<InspectorState>>>,
+}
+
+fn update_actions(
+    controls: Res<Controls>,
+    mut sources: InputSources,
+    mut state: ResMut<ActionState>,
+) {
+    let state = &mut *state;
+    state.previous = std::mem::take(&mut state.values);
+    state.motion.clear();
+
+    let mouse_delta = sources
+        .mouse_motion
+        .iter()
+        .fold(Vec2::ZERO, |delta, ev| delta + ev.delta);
+    let mouse_free = sources
+        .inspector
+        .as_ref()
+        .is_none_or(|state| *state.get() != InspectorState::Active);
+    let gamepads: Vec<_> = sources
+        .gamepads
+        .iter()
+        .flat_map(|gamepads| gamepads.iter())
+        .collect();
+
+    for (action, binding) in &controls.bindings {
+        let value = match *binding {
+            Binding::Key(key) => sources
+                .keys
+                .as_ref()
+                .map_or(0.0, |keys| keys.pressed(key) as u8 as f32),
+            Binding::Mouse(_) | Binding::MouseMotion(..) if !mouse_free => continue,
+            Binding::Mouse(button) => sources
+                .mouse_buttons
+                .as_ref()
+                .map_or(0.0, |buttons| buttons.pressed(button) as u8 as f32),
+            Binding::MouseMotion(axis, sign) => {
+                let delta = match axis {
+                    MouseAxis::X => mouse_delta.x,
+                    MouseAxis::Y => mouse_delta.y,
+                };
+                *state.motion.entry(*action).or_default() += sign.half(delta);
+                continue;
+            }
+            Binding::GamepadButton(button_type) => {
+                sources.gamepad_buttons.as_ref().map_or(0.0, |buttons| {
+                    gamepads
+                        .iter()
+                        .any(|gamepad| buttons.pressed(GamepadButton::new(*gamepad, button_type)))
+                        as u8 as f32
+                })
+            }
+            Binding::GamepadAxis(axis_type, sign) => {
+                sources.gamepad_axes.as_ref().map_or(0.0, |axes| {
+                    gamepads
+                        .iter()
+                        .filter_map(|gamepad| axes.get(GamepadAxis::new(*gamepad, axis_type)))
+                        .map(|value| sign.half(value))
+                        .filter(|value| *value > controls.deadzone)
+                        .fold(0.0, f32::max)
+                })
+            }
+        };
+        let entry = state.values.entry(*action).or_default();
+        *entry = entry.max(value);
+    }
+}
@@ -1,7 +1,7 @@
 use bevy::{
     diagnostic::{Diagnostic, DiagnosticsStore},
-    input::common_conditions::input_toggle_active,
     prelude::*,
+    window::{CursorGrabMode, PrimaryWindow},
 };
 use bevy_egui::{egui, EguiContexts};
 use bevy_inspector_egui::{
@@ -10,7 +10,10 @@ use bevy_inspector_egui::{
 };
 use std::time::Instant;
 
-use crate::camera::CameraControlSettings;
+use crate::{
+    camera::CameraControlSettings,
+    controls::{Action, ActionState},
+};
 
 #[derive(Reflect, Resource, Default, Debug)]
 #[reflect(Resource)]
@@ -19,9 +22,11 @@ struct Inspector {
 }
 
 #[derive(States, Reflect, Default, Debug, Clone, Eq, PartialEq, Hash)]
-enum InspectorState {
-    Active,
+pub enum InspectorState {
+    /// Inspector windows shown and the cursor free to use them.
     #[default]
+    Active,
+    /// Cursor grabbed and hidden, for looking around.
     Inactive,
 }
 
@@ -30,28 +35,51 @@ pub struct InspectorPlugin;
 impl Plugin for InspectorPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(bevy_egui::EguiPlugin)
+            .add_plugins(WorldInspectorPlugin::default().run_if(in_state(InspectorState::Active)))
             .add_plugins(
-                WorldInspectorPlugin::default().run_if(input_toggle_active(true, KeyCode::Escape)),
+                ResourceInspectorPlugin::<Inspector>::new()
+                    .run_if(in_state(InspectorState::Active)),
             )
-            .add_plugins(ResourceInspectorPlugin::<Inspector>::new())
             .register_type::<Inspector>()
-            // .add_systems(Update, toggle)
+            .add_state::<InspectorState>()
+            .add_systems(Update, toggle.run_if(resource_exists::<ActionState>()))
             .add_systems(Update, ui)
-            // .add_system_set(SystemSet::on_enter(InspectorState::Active).with_system(activate))
-            // .add_system_set(SystemSet::on_enter(InspectorState::Inactive).with_system(deactivate))
-            .add_state::<InspectorState>();
+            .add_systems(OnEnter(InspectorState::Active), release_cursor)
+            .add_systems(OnEnter(InspectorState::Inactive), grab_cursor);
+    }
+}
+
+fn toggle(
+    actions: Res<ActionState>,
+    state: Res<State<InspectorState>>,
+    mut next_state: ResMut<NextState<InspectorState>>,
+) {
+    if actions.just_pressed(Action::ToggleInspector) {
+        next_state.set(match state.get() {
+            InspectorState::Active => InspectorState::Inactive,
+            InspectorState::Inactive => InspectorState::Active,
+        });
     }
 }
 
-// fn toggle(key: Res<Input<KeyCode>>, mut state: ResMut<State<InspectorState>>) {
-//     if key.just_pressed(KeyCode::Escape) {
-//         let next_state = match state.get() {
-//             InspectorState::Active => InspectorState::Inactive,
-//             _ => InspectorState::Active,
-//         };
-//         state.set(next_state).ok();
-//     }
-// }
+fn grab_cursor(windows: Query<&mut Window, With<PrimaryWindow>>) {
+    set_cursor_grab(true, windows);
+}
+
+fn release_cursor(windows: Query<&mut Window, With<PrimaryWindow>>) {
+    set_cursor_grab(false, windows);
+}
+
+fn set_cursor_grab(grab: bool, mut windows: Query<&mut Window, With<PrimaryWindow>>) {
+    for mut window in &mut windows {
+        window.cursor.grab_mode = if grab {
+            CursorGrabMode::Locked
+        } else {
+            CursorGrabMode::None
+        };
+        window.cursor.visible = !grab;
+    }
+}
 
 fn ui(
     mut egui_ctx: EguiContexts,
@@ -65,7 +93,8 @@ fn ui(
     egui::Window::new("Metrics")
         .resizable(true)
         .show(egui_ctx.ctx_mut(), |ui| {
-            diagnostics_ui(ui, diagnostics, time.startup())
+            egui::ScrollArea::vertical()
+                .show(ui, |ui| diagnostics_ui(ui, diagnostics, time.startup()));
         });
 }
 
@@ -104,20 +133,3 @@ fn diagnostic_ui(ui: &mut Ui, diagnostic: &Diagnostic, start_time: Instant) {
     }
     ui.end_row();
 }
-
-// fn activate(world_inspector: ResMut<WorldInspectorParams>, inspectors: ResMut<InspectorWindows>) {
-//     update_active(true, world_inspector, inspectors);
-// }
-
-// fn deactivate(world_inspector: ResMut<WorldInspectorParams>, inspectors: ResMut<InspectorWindows>) {
-//     update_active(false, world_inspector, inspectors);
-// }
-
-// fn update_active(
-//     active: bool,
-//     mut world_inspector: ResMut<WorldInspectorParams>,
-//     mut inspectors: ResMut<InspectorWindows>,
-// ) {
-//     world_inspector.enabled = active;
-//     inspectors.window_data_mut::<Inspector>().visible = active;
-// }
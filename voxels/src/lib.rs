@@ -0,0 +1,5 @@
+pub mod camera;
+pub mod cave;
+pub mod controls;
+pub mod inspector;
+pub mod player;
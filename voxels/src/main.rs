@@ -1,9 +1,6 @@
 use bevy::{diagnostic, prelude::*};
 
-mod camera;
-mod cave;
-mod inspector;
-mod player;
+use voxels::{camera, cave, controls, inspector, player};
 
 fn main() {
     App::new()
@@ -18,9 +15,11 @@ fn main() {
         // .add_plugin(diagnostic::LogDiagnosticsPlugin::default())
         // .add_plugin(WireframePlugin)
         .add_plugins((
+            controls::ControlsPlugin,
             inspector::InspectorPlugin,
             camera::CameraPlugin,
             cave::CavePlugin,
+            player::PlayerPlugin,
         ))
         .add_systems(Startup, setup)
         .run();
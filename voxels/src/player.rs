@@ -2,9 +2,23 @@ use bevy::prelude::*;
 
 use crate::camera::CameraBundle;
 
+use self::walk::{CharacterController, PlayerMode};
+
+pub mod walk;
+
+pub struct PlayerPlugin;
+
+impl Plugin for PlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<Player>().add_plugins(walk::WalkPlugin);
+    }
+}
+
 #[derive(Bundle)]
 pub struct PlayerBundle {
     player: Player,
+    mode: PlayerMode,
+    controller: CharacterController,
     camera: CameraBundle,
 }
 
@@ -14,6 +28,8 @@ impl PlayerBundle {
     pub fn new(pos: Vec3, look_target: Vec3) -> Self {
         Self {
             player: Player,
+            mode: PlayerMode::default(),
+            controller: CharacterController::default(),
             camera: CameraBundle::new(pos + Vec3::new(0.0, EYE_HEIGHT, 0.0), look_target),
         }
     }
@@ -0,0 +1,304 @@
+use bevy::prelude::*;
+
+use crate::{
+    camera::{CameraControlEvent, CameraController},
+    cave::world::CaveWorld,
+    controls::{Action, ActionState},
+};
+
+use super::Player;
+
+pub struct WalkPlugin;
+
+impl Plugin for WalkPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<WalkSettings>()
+            .register_type::<PlayerMode>()
+            .register_type::<CharacterController>()
+            .insert_resource(WalkSettings::default())
+            .add_event::<CameraControlEvent>()
+            .add_systems(
+                Update,
+                (toggle_mode.run_if(resource_exists::<ActionState>()), walk).chain(),
+            );
+    }
+}
+
+/// Bisection steps when moving up to an obstacle.
+const CONTACT_ITERATIONS: usize = 10;
+/// Clearance kept between the capsule and voxels.
+const SKIN: f32 = 0.001;
+/// Longest frame simulated in one go, so hitches don't launch the player.
+const MAX_DELTA_SECONDS: f32 = 0.1;
+
+#[derive(Resource, Reflect, Debug)]
+#[reflect(Resource)]
+pub struct WalkSettings {
+    pub radius: f32,
+    pub height: f32,
+    pub crouch_height: f32,
+    /// Distance from the top of the capsule down to the camera.
+    pub eye_offset: f32,
+    pub gravity: f32,
+    pub jump_speed: f32,
+    pub max_fall_speed: f32,
+    pub walk_speed: f32,
+    pub sprint_factor: f32,
+    pub crouch_factor: f32,
+    /// Horizontal acceleration on the ground, and in the air scaled by
+    /// `air_control`.
+    pub acceleration: f32,
+    pub air_control: f32,
+    /// Tallest ledge walked onto without jumping.
+    pub step_height: f32,
+}
+
+impl Default for WalkSettings {
+    fn default() -> Self {
+        Self {
+            radius: 0.3,
+            height: 1.8,
+            crouch_height: 1.0,
+            eye_offset: 0.2,
+            gravity: 9.81,
+            jump_speed: 4.5,
+            max_fall_speed: 30.0,
+            walk_speed: 3.0,
+            sprint_factor: 2.0,
+            crouch_factor: 0.5,
+            acceleration: 30.0,
+            air_control: 0.2,
+            step_height: 0.25,
+        }
+    }
+}
+
+#[derive(Component, Reflect, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[reflect(Component)]
+pub enum PlayerMode {
+    /// Free flight through the rock.
+    #[default]
+    Fly,
+    /// A capsule with gravity that collides with the voxelized chunks.
+    Walk,
+}
+
+#[derive(Component, Reflect, Debug, Default)]
+#[reflect(Component)]
+pub struct CharacterController {
+    pub velocity: Vec3,
+    pub grounded: bool,
+    pub crouching: bool,
+}
+
+fn toggle_mode(
+    actions: Res<ActionState>,
+    mut players: Query<
+        (
+            &mut PlayerMode,
+            &mut CharacterController,
+            &mut CameraController,
+        ),
+        With<Player>,
+    >,
+) {
+    if !actions.just_pressed(Action::ToggleWalk) {
+        return;
+    }
+
+    for (mut mode, mut controller, mut camera_controller) in &mut players {
+        *mode = match *mode {
+            PlayerMode::Fly => PlayerMode::Walk,
+            PlayerMode::Walk => PlayerMode::Fly,
+        };
+        *controller = CharacterController::default();
+        camera_controller.translate = *mode == PlayerMode::Fly;
+    }
+}
+
+fn walk(
+    settings: Res<WalkSettings>,
+    time: Res<Time>,
+    cave_world: CaveWorld,
+    mut events: EventReader<CameraControlEvent>,
+    mut players: Query<(&PlayerMode, &mut CharacterController, &mut Transform), With<Player>>,
+) {
+    let (mut direction, mut sprint) = (Vec3::ZERO, false);
+    for ev in events.iter() {
+        direction += ev.delta_translation;
+        sprint |= ev.sprint;
+    }
+
+    let dt = time.delta_seconds().min(MAX_DELTA_SECONDS);
+    for (mode, mut controller, mut transform) in &mut players {
+        if *mode != PlayerMode::Walk || dt == 0.0 {
+            continue;
+        }
+
+        let height = |crouching| {
+            if crouching {
+                settings.crouch_height
+            } else {
+                settings.height
+            }
+        };
+        let mut feet =
+            transform.translation - Vec3::Y * (height(controller.crouching) - settings.eye_offset);
+
+        // Wait for the ground to be voxelized rather than fall through it.
+        if !cave_world.is_voxelized(feet) {
+            continue;
+        }
+
+        // Horizontal intent in the camera's yaw frame, jump and crouch on the
+        // vertical axis.
+        let yaw = transform.rotation.to_euler(EulerRot::YXZ).0;
+        let intent = Quat::from_rotation_y(yaw) * Vec3::new(direction.x, 0.0, direction.z);
+        let jump = direction.y > 0.0;
+        let crouch = direction.y < 0.0;
+
+        let capsule = Capsule {
+            radius: settings.radius,
+            cave_world: &cave_world,
+        };
+
+        if crouch {
+            controller.crouching = true;
+        } else if controller.crouching && !capsule.overlaps(feet, settings.height) {
+            controller.crouching = false;
+        }
+        let height = height(controller.crouching);
+
+        let speed = settings.walk_speed
+            * if controller.crouching {
+                settings.crouch_factor
+            } else if sprint {
+                settings.sprint_factor
+            } else {
+                1.0
+            };
+        let target = intent.normalize_or_zero() * speed;
+        let acceleration = settings.acceleration
+            * if controller.grounded {
+                1.0
+            } else {
+                settings.air_control
+            };
+        let horizontal = Vec3::new(controller.velocity.x, 0.0, controller.velocity.z);
+        let change = (target - horizontal).clamp_length_max(acceleration * dt);
+        controller.velocity += change;
+
+        if jump && controller.grounded && !controller.crouching {
+            controller.velocity.y = settings.jump_speed;
+        }
+        controller.velocity.y =
+            (controller.velocity.y - settings.gravity * dt).max(-settings.max_fall_speed);
+
+        // Rock edited onto the player, or meshed around them while flying,
+        // pushes them up rather than trapping them.
+        if capsule.overlaps(feet, height) {
+            if let Some(free) = (1..=8)
+                .map(|i| feet + Vec3::Y * height * i as f32 / 8.0)
+                .find(|feet| !capsule.overlaps(*feet, height))
+            {
+                feet = free;
+            }
+            controller.velocity = Vec3::ZERO;
+        } else {
+            let delta = controller.velocity * dt;
+            let substeps = (delta.length() / settings.radius).ceil().max(1.0) as usize;
+            let step = delta / substeps as f32;
+            let was_grounded = controller.grounded;
+            for _ in 0..substeps {
+                let (moved, blocked) = capsule.sweep(feet, Vec3::Y * step.y, height);
+                feet = moved;
+                if blocked {
+                    controller.grounded = step.y < 0.0;
+                    controller.velocity.y = 0.0;
+                } else if step.y != 0.0 {
+                    controller.grounded = false;
+                }
+
+                for axis in [Vec3::X, Vec3::Z] {
+                    let along = axis * step.dot(axis);
+                    let (moved, blocked) = capsule.sweep(feet, along, height);
+                    feet = if !blocked {
+                        moved
+                    } else if let Some(stepped) = (was_grounded || controller.grounded)
+                        .then(|| capsule.step_up(feet, along, height, settings.step_height))
+                        .flatten()
+                    {
+                        stepped
+                    } else {
+                        let velocity = controller.velocity;
+                        controller.velocity -= axis * velocity.dot(axis);
+                        moved
+                    };
+                }
+            }
+        }
+
+        transform.translation = feet + Vec3::Y * (height - settings.eye_offset);
+    }
+}
+
+/// Upright capsule standing on `feet`, against the solid voxels of the cave.
+struct Capsule<'a, 'w, 's> {
+    radius: f32,
+    cave_world: &'a CaveWorld<'w, 's>,
+}
+
+impl Capsule<'_, '_, '_> {
+    /// Only looks at the voxels within the capsule's bounds.
+    fn overlaps(&self, feet: Vec3, height: f32) -> bool {
+        let (bottom, top) = (feet.y + self.radius, feet.y + height - self.radius);
+        let radius = self.radius + SKIN;
+        let bounds = (
+            Vec3::new(feet.x - radius, bottom - radius, feet.z - radius),
+            Vec3::new(feet.x + radius, top + radius, feet.z + radius),
+        );
+        self.cave_world.any_solid(bounds.0, bounds.1, |min, max| {
+            let d = Vec3::new(
+                (min.x - feet.x).max(feet.x - max.x).max(0.0),
+                (min.y - top).max(bottom - max.y).max(0.0),
+                (min.z - feet.z).max(feet.z - max.z).max(0.0),
+            );
+            d.length_squared() < radius * radius
+        })
+    }
+
+    /// Moves by `delta` up to the first contact, returning where it got to
+    /// and whether it was blocked.
+    fn sweep(&self, feet: Vec3, delta: Vec3, height: f32) -> (Vec3, bool) {
+        if delta == Vec3::ZERO || !self.overlaps(feet + delta, height) {
+            return (feet + delta, false);
+        }
+
+        let (mut free, mut blocked) = (0.0, 1.0);
+        for _ in 0..CONTACT_ITERATIONS {
+            let mid = (free + blocked) / 2.0;
+            if self.overlaps(feet + delta * mid, height) {
+                blocked = mid;
+            } else {
+                free = mid;
+            }
+        }
+        (feet + delta * free, true)
+    }
+
+    /// Climbs a ledge no taller than `step_height` in the way of `delta`.
+    fn step_up(&self, feet: Vec3, delta: Vec3, height: f32, step_height: f32) -> Option<Vec3> {
+        let raised = feet + Vec3::Y * step_height;
+        // The rounded bottom would slide over the corner of a taller ledge, so
+        // its top has to be clear a radius ahead too.
+        let ahead = raised + delta.normalize_or_zero() * self.radius;
+        if self.overlaps(raised, height)
+            || self.overlaps(raised + delta, height)
+            || self.overlaps(ahead, height)
+        {
+            return None;
+        }
+        let (landed, _) = self.sweep(raised + delta, -Vec3::Y * step_height, height);
+        Some(landed)
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use voxels::{
+    camera::{
+        rig::{CameraKeyframe, CameraMode, CameraPath, CameraRig, CameraRigSettings},
+        CameraBundle, CameraControlEvent, CameraPlugin,
+    },
+    player::{Player, PlayerBundle},
+};
+
+fn camera_app(hz: u32) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, CameraPlugin))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1.0 / hz as f64,
+        )));
+    app
+}
+
+/// Flies forwards for `seconds` then coasts for as long, at `hz` frames per
+/// second, and returns where the camera ends up.
+fn fly(hz: u32, seconds: u32, sprint: bool) -> Vec3 {
+    let mut app = camera_app(hz);
+    let camera = app
+        .world
+        .spawn(CameraBundle::new(Vec3::ZERO, -Vec3::Z))
+        .id();
+
+    // The first update only starts the clock.
+    app.update();
+    for _ in 0..hz * seconds {
+        app.world.send_event(CameraControlEvent {
+            delta_rotation: Vec2::ZERO,
+            delta_translation: -Vec3::Z,
+            sprint,
+        });
+        app.update();
+    }
+    for _ in 0..hz * seconds {
+        app.update();
+    }
+    app.world.get::<Transform>(camera).unwrap().translation
+}
+
+#[test]
+fn flight_is_frame_rate_independent() {
+    for sprint in [false, true] {
+        let slow = fly(60, 2, sprint);
+        let fast = fly(240, 2, sprint);
+        assert!(slow.z < -1.0, "{slow}");
+        assert!(
+            slow.distance(fast) < 0.02 * slow.length(),
+            "{slow} at 60 Hz, {fast} at 240 Hz"
+        );
+    }
+}
+
+/// Switches mode and runs for `frames` at 60 Hz, returning which cameras are
+/// rendering and where the rig is.
+fn switch(app: &mut App, mode: CameraMode, frames: usize) -> (bool, bool, Transform) {
+    app.world.resource_mut::<NextState<CameraMode>>().set(mode);
+    for _ in 0..frames {
+        app.update();
+    }
+    let mut players = app.world.query_filtered::<&Camera, With<Player>>();
+    let player = players.single(&app.world).is_active;
+    let mut rigs = app.world.query::<(&Camera, &Transform, &CameraRig)>();
+    let (rig, transform, _) = rigs.single(&app.world);
+    (player, rig.is_active, *transform)
+}
+
+#[test]
+fn modes_hand_over_smoothly() {
+    let mut app = camera_app(60);
+    let player = app
+        .world
+        .spawn(PlayerBundle::new(Vec3::ZERO, -Vec3::Z))
+        .id();
+    app.update();
+    app.update();
+    let eye = *app.world.get::<Transform>(player).unwrap();
+    let offset = app.world.resource::<CameraRigSettings>().follow_offset;
+    let behind = eye.transform_point(offset);
+
+    // The rig takes over at once, starting from the player's view.
+    let (player_active, rig_active, rig) = switch(&mut app, CameraMode::ThirdPerson, 1);
+    assert!(!player_active && rig_active);
+    assert!(rig.translation.distance(eye.translation) < 0.1 * offset.length());
+
+    let (_, _, rig) = switch(&mut app, CameraMode::ThirdPerson, 60);
+    assert!(rig.translation.distance(behind) < 1e-3, "{rig:?}");
+    assert!(rig.rotation.angle_between(eye.rotation) < 1e-3);
+
+    // Orbiting starts from where the view is.
+    let (_, rig_active, rig) = switch(&mut app, CameraMode::Orbit, 60);
+    assert!(rig_active);
+    assert!(rig.translation.distance(behind) < 1e-3, "{rig:?}");
+
+    // The player's camera only takes over again once the rig is back at the
+    // player's view.
+    let (player_active, rig_active, _) = switch(&mut app, CameraMode::FirstPerson, 10);
+    assert!(!player_active && rig_active);
+    let (player_active, rig_active, rig) = switch(&mut app, CameraMode::FirstPerson, 60);
+    assert!(player_active && !rig_active);
+    assert!(rig.translation.distance(eye.translation) < 1e-3);
+}
+
+#[test]
+fn paths_pass_through_their_keyframes() {
+    let keyframe = |time, x: f32, yaw: f32| CameraKeyframe {
+        time,
+        translation: Vec3::new(x, x * x, 0.0),
+        rotation: Quat::from_rotation_y(yaw),
+    };
+    let path = CameraPath {
+        keyframes: vec![
+            keyframe(0.0, 0.0, 0.0),
+            keyframe(2.0, 1.0, 0.5),
+            keyframe(3.0, 2.0, 1.0),
+            keyframe(5.0, 3.0, 0.5),
+        ],
+    };
+    assert_eq!(CameraPath::default().sample(1.0), None);
+
+    for keyframe in &path.keyframes {
+        let view = path.sample(keyframe.time).unwrap();
+        assert!(view.translation.distance(keyframe.translation) < 1e-5);
+        assert!(view.rotation.angle_between(keyframe.rotation) < 1e-3);
+    }
+    assert_eq!(path.sample(-1.0), path.sample(0.0));
+    assert_eq!(path.sample(9.0), path.sample(5.0));
+
+    // No jumps between frames, even across keyframes.
+    let mut previous = path.sample(0.0).unwrap();
+    for i in 1..=500 {
+        let view = path.sample(i as f32 / 100.0).unwrap();
+        assert!(view.translation.distance(previous.translation) < 0.05);
+        assert!(view.rotation.angle_between(previous.rotation) < 0.02);
+        previous = view;
+    }
+}
@@ -0,0 +1,29 @@
+use std::{env, fs, process};
+
+use voxels::controls::Controls;
+
+#[test]
+fn missing_controls_are_written_from_the_defaults() {
+    let path = env::temp_dir().join(format!("voxels-controls-{}.ron", process::id()));
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(Controls::load(&path), Controls::default());
+    let text = fs::read_to_string(&path).expect("generated controls");
+    assert!(text.contains("(Dig, Mouse(Left)),"), "{text}");
+    assert_eq!(Controls::load(&path), Controls::default());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn saved_controls_load_back() {
+    let path = env::temp_dir().join(format!("voxels-controls-saved-{}.ron", process::id()));
+    let mut controls = Controls {
+        deadzone: 0.3,
+        ..Controls::default()
+    };
+    controls.bindings.truncate(4);
+    controls.save(&path).unwrap();
+
+    assert_eq!(Controls::load(&path), controls);
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,268 @@
+use std::{env, fs, process};
+
+use bevy::prelude::*;
+use voxels::cave::{
+    chunk::{CaveChunk, CaveChunkSamples, CaveChunkSettings},
+    edit::CaveEditShape,
+    generator::{
+        CaveGenerator, CaveSampleGrid, CsgGenerator, CsgOp, NoiseGenerator, VolumeGenerator,
+    },
+    worm::CaveWormSettings,
+};
+
+const GRID: CaveSampleGrid = CaveSampleGrid {
+    origin: Vec3::ZERO,
+    voxel_size: 0.5,
+    length: 18,
+};
+
+fn samples(generator: &dyn CaveGenerator, settings: &CaveChunkSettings) -> (Vec<f32>, Vec<u8>) {
+    let (mut density, mut materials) = (vec![0.0; GRID.len()], vec![0; GRID.len()]);
+    generator.generate(settings, &GRID, &mut density, &mut materials);
+    (density, materials)
+}
+
+#[test]
+fn csg_shapes_carve_and_fill() {
+    let settings = CaveChunkSettings::default();
+    let center = Vec3::splat(4.0);
+    let hole = CsgGenerator::new(NoiseGenerator).with(
+        CsgOp::Subtract,
+        CaveEditShape::Sphere { radius: 2.0 },
+        center,
+        0,
+    );
+    let pillar = CsgGenerator::new(NoiseGenerator).with(
+        CsgOp::Union,
+        CaveEditShape::Sphere { radius: 2.0 },
+        center,
+        1,
+    );
+
+    let (carved, _) = samples(&hole, &settings);
+    let (filled, materials) = samples(&pillar, &settings);
+    let mut inside = 0;
+    for i in 0..GRID.len() {
+        if GRID.position(i).distance(center) < 1.5 {
+            inside += 1;
+            assert!(carved[i] < settings.threshold);
+            assert!(filled[i] > settings.threshold);
+            assert_eq!(materials[i], 1);
+        }
+    }
+    assert!(inside > 0);
+}
+
+/// Loads a MagicaVoxel model of `size`, z up, from (x, y, z, color index)
+/// voxels, with model voxels 2 wide from the origin.
+fn vox_volume(size: [u32; 3], voxels: &[[u8; 4]]) -> VolumeGenerator {
+    let mut content = Vec::new();
+    let mut chunk = |id: &[u8], data: &[u32], bytes: &[u8]| {
+        let body: Vec<u8> = data
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .chain(bytes.iter().copied())
+            .collect();
+        content.extend(id);
+        content.extend((body.len() as u32).to_le_bytes());
+        content.extend(0_u32.to_le_bytes());
+        content.extend(body);
+    };
+    chunk(b"SIZE", &size, &[]);
+    chunk(b"XYZI", &[voxels.len() as u32], &voxels.concat());
+    let mut file = b"VOX ".to_vec();
+    file.extend(150_u32.to_le_bytes());
+    file.extend(b"MAIN");
+    file.extend(0_u32.to_le_bytes());
+    file.extend((content.len() as u32).to_le_bytes());
+    file.extend(content);
+
+    let path = env::temp_dir().join(format!("voxels-volume-{}.vox", process::id()));
+    fs::write(&path, file).unwrap();
+    let volume = VolumeGenerator::load(&path, NoiseGenerator, Vec3::ZERO, 2.0);
+    fs::remove_file(&path).unwrap();
+    volume.unwrap()
+}
+
+#[test]
+fn volume_stamps_vox_model() {
+    let settings = CaveChunkSettings::default();
+    let inside = |position: Vec3| {
+        !(position.cmplt(Vec3::ZERO).any() || position.cmpge(Vec3::splat(4.0)).any())
+    };
+
+    // A 2x2x2 model with only its bottom layer set.
+    let layer = vox_volume(
+        [2, 2, 2],
+        &[[0, 0, 0, 2], [1, 0, 0, 2], [0, 1, 0, 2], [1, 1, 0, 2]],
+    );
+    let (density, materials) = samples(&layer, &settings);
+    for i in 0..GRID.len() {
+        let position = GRID.position(i);
+        if !inside(position) {
+            continue;
+        }
+        if position.y < 2.0 {
+            assert!(density[i] > settings.threshold);
+            assert_eq!(materials[i], 2);
+        } else {
+            assert!(density[i] < settings.threshold);
+        }
+    }
+
+    // Turning z up into y up must not mirror the model: MagicaVoxel's +x,
+    // +y and +z become +x, -z and +y.
+    let corners = vox_volume(
+        [2, 2, 2],
+        &[[0, 0, 0, 2], [1, 0, 0, 3], [0, 1, 0, 4], [0, 0, 1, 5]],
+    );
+    let (density, materials) = samples(&corners, &settings);
+    for i in 0..GRID.len() {
+        let position = GRID.position(i);
+        if !inside(position) {
+            continue;
+        }
+        let expected = match (position / 2.0).floor().as_ivec3().to_array() {
+            [0, 0, 1] => 2,
+            [1, 0, 1] => 3,
+            [0, 0, 0] => 4,
+            [0, 1, 1] => 5,
+            _ => 0,
+        };
+        assert_eq!(density[i] > settings.threshold, expected != 0);
+        if expected != 0 {
+            assert_eq!(materials[i], expected);
+        }
+    }
+}
+
+/// Rock everywhere, so only tunnels are empty.
+struct Solid;
+
+impl CaveGenerator for Solid {
+    fn generate(
+        &self,
+        settings: &CaveChunkSettings,
+        _grid: &CaveSampleGrid,
+        density: &mut [f32],
+        materials: &mut [u8],
+    ) {
+        density.fill(settings.threshold + 1.0);
+        materials.fill(1);
+    }
+
+    fn fingerprint(&self) -> u64 {
+        0
+    }
+}
+
+#[test]
+fn worms_carve_tunnels_across_chunks() {
+    let settings = CaveChunkSettings {
+        size: 4.0,
+        ..default()
+    };
+    let worms = settings
+        .worms
+        .worms(settings.seed, Vec3::splat(-64.0), Vec3::splat(64.0));
+    let (centre, _) = worms[0].points[worms[0].points.len() / 2];
+    let origin = (centre / settings.size).floor() * settings.size;
+
+    let subdivisions = 4;
+    let n = 2_usize.pow(subdivisions) + 2;
+    let chunk = CaveChunk::generate(&Solid, &settings, origin, subdivisions, 0);
+    let next = CaveChunk::generate(
+        &Solid,
+        &settings,
+        origin + Vec3::X * settings.size,
+        subdivisions,
+        0,
+    );
+    let (chunk, next) = (chunk.samples.read().unwrap(), next.samples.read().unwrap());
+    let (chunk, _) = chunk.expanded(&settings, n.pow(3));
+    let (next, _) = next.expanded(&settings, n.pow(3));
+
+    assert!(chunk.iter().any(|sample| *sample < settings.threshold));
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..2 {
+                let shared = (n - 2 + x) + y * n + z * n * n;
+                let (a, b) = (chunk[shared], next[x + y * n + z * n * n]);
+                assert_eq!(a > settings.threshold, b > settings.threshold);
+                if a < settings.threshold + 1.0 {
+                    assert_eq!(a, b);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn worms_carve_grids_coarser_than_their_tunnels() {
+    let settings = CaveChunkSettings {
+        size: 64.0,
+        ..default()
+    };
+    let worms = settings
+        .worms
+        .worms(settings.seed, Vec3::splat(-64.0), Vec3::splat(64.0));
+    let (centre, _) = worms[0].points[worms[0].points.len() / 2];
+    let origin = (centre / settings.size).floor() * settings.size;
+
+    // Voxels of 8, several times the tunnel width.
+    let subdivisions = 3;
+    let n = 2_usize.pow(subdivisions) + 2;
+    let chunk = CaveChunk::generate(&Solid, &settings, origin, subdivisions, 0);
+    let chunk = chunk.samples.read().unwrap();
+    let (chunk, _) = chunk.expanded(&settings, n.pow(3));
+    assert!(chunk.iter().any(|sample| *sample < settings.threshold));
+}
+
+/// Rock everywhere, its palette id changing every unit along x.
+struct Striped;
+
+impl CaveGenerator for Striped {
+    fn generate(
+        &self,
+        settings: &CaveChunkSettings,
+        grid: &CaveSampleGrid,
+        density: &mut [f32],
+        materials: &mut [u8],
+    ) {
+        density.fill(settings.threshold + 1.0);
+        for (i, material) in materials.iter_mut().enumerate() {
+            *material = 1 + (grid.position(i).x.floor() as i32).rem_euclid(2) as u8;
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        0
+    }
+}
+
+#[test]
+fn solid_chunks_keep_their_materials() {
+    let settings = CaveChunkSettings {
+        size: 4.0,
+        worms: CaveWormSettings {
+            per_cell: 0.0,
+            ..default()
+        },
+        ..default()
+    };
+
+    // Fully sampled rock of several materials isn't stored as one.
+    let (density, materials) = samples(&Striped, &settings);
+    assert!(matches!(
+        CaveChunkSamples::new(&settings, density, materials),
+        CaveChunkSamples::Mixed { .. }
+    ));
+
+    // The pre-pass only keeps one, until the chunk is expanded for editing.
+    let cave_chunk = CaveChunk::generate(&Striped, &settings, Vec3::ZERO, 4, 0);
+    let mut samples = cave_chunk.samples.write().unwrap();
+    assert!(matches!(*samples, CaveChunkSamples::Solid(_)));
+    let (density, materials) = cave_chunk.expand(&mut samples, &Striped, Vec3::ZERO);
+    assert!(density.iter().all(|d| *d > settings.threshold));
+    assert!(materials.contains(&1) && materials.contains(&2));
+}
@@ -0,0 +1,841 @@
+use std::{
+    env, fs,
+    hash::Hasher,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    asset::AssetPlugin,
+    diagnostic::DiagnosticsStore,
+    ecs::system::SystemState,
+    input::InputPlugin,
+    prelude::*,
+    render::mesh::{Indices, VertexAttributeValues},
+    time::TimeUpdateStrategy,
+};
+use block_mesh::ndshape::Shape;
+use voxels::{
+    camera::{CameraControlEvent, CameraController},
+    cave::{
+        chunk::{CaveChunk, CaveChunkFill, CaveChunkSettings, CaveMeshMode},
+        diagnostics::CaveDiagnosticsPlugin,
+        edit::{CaveEdit, CaveEditAction, CaveEditShape, PendingCaveEdits},
+        generator::{AddCaveGenerator, CsgGenerator, CsgOp, NoiseGenerator, StableHasher},
+        index::CaveChunkIndex,
+        region::CaveRegionStore,
+        schedule::{CaveChunkScheduler, CaveChunkStage, CaveScheduleSettings},
+        stream::{CaveStreamSettings, StreamedCaveChunks},
+        voxelize::{CaveChunkVoxels, CaveVoxel},
+        world::CaveWorld,
+        CavePlugin,
+    },
+    controls::{Controls, ControlsPlugin},
+    player::{
+        walk::{CharacterController, PlayerMode, WalkSettings},
+        Player, PlayerPlugin,
+    },
+};
+
+/// Mesh positions are hashed in steps of one over this.
+const POSITION_QUANTUM: f32 = 1000.0;
+/// How long chunk jobs get to finish, however loaded the machine is.
+const JOB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Totals over every chunk streamed around the origin.
+#[derive(Debug, PartialEq, Eq)]
+struct CaveSummary {
+    chunks: usize,
+    voxels: usize,
+    triangles: usize,
+    hash: u64,
+}
+
+fn headless_app(settings: CaveChunkSettings) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, AssetPlugin::default(), CavePlugin))
+        .insert_resource(settings)
+        .insert_resource(CaveRegionStore::disabled())
+        .insert_resource(CaveStreamSettings {
+            max_lod: 0,
+            root_radius: 1,
+            max_subdivisions: 4,
+            ..default()
+        });
+    app.world.spawn((Player, TransformBundle::default()));
+    app
+}
+
+/// Runs the app until every streamed chunk is ready and meshed.
+fn generated_app(settings: CaveChunkSettings) -> App {
+    let mut app = headless_app(settings);
+    wait_for_chunks(&mut app);
+    app
+}
+
+/// Runs the app until every streamed chunk is ready and no jobs or edits are
+/// left.
+fn wait_for_chunks(app: &mut App) {
+    let deadline = Instant::now() + JOB_TIMEOUT;
+    let mut ready = false;
+    while Instant::now() < deadline {
+        app.update();
+        let streamed = app.world.resource::<StreamedCaveChunks>();
+        let scheduler = app.world.resource::<CaveChunkScheduler>();
+        ready = !streamed.loaded.is_empty()
+            && streamed.loaded.len() == streamed.wanted.len()
+            && streamed.loaded.values().all(|(entity, _)| {
+                app.world.get::<CaveChunkStage>(*entity) == Some(&CaveChunkStage::Ready)
+            })
+            && scheduler.queued_len() == 0
+            && scheduler.running_stages().next().is_none()
+            && app.world.resource::<PendingCaveEdits>().is_empty();
+        if ready {
+            break;
+        }
+        thread::yield_now();
+    }
+    assert!(ready, "cave chunks never became ready");
+
+    // Meshes are attached the frame after a chunk is ready.
+    app.update();
+}
+
+fn solid_at(app: &mut App, position: Vec3) -> bool {
+    let mut state = SystemState::<CaveWorld>::new(&mut app.world);
+    let cave_world = state.get(&app.world);
+    let hit = cave_world.raycast(position, Vec3::X, 0.01);
+    hit.is_some_and(|hit| hit.distance == 0.0)
+}
+
+fn generate(settings: CaveChunkSettings) -> CaveSummary {
+    summarize(&generated_app(settings).world)
+}
+
+fn summarize(world: &World) -> CaveSummary {
+    let streamed = world.resource::<StreamedCaveChunks>();
+    let meshes = world.resource::<Assets<Mesh>>();
+
+    let mut keys: Vec<_> = streamed.loaded.keys().collect();
+    keys.sort_by_key(|key| (key.lod, key.coord.to_array()));
+
+    let mut summary = CaveSummary {
+        chunks: keys.len(),
+        voxels: 0,
+        triangles: 0,
+        hash: 0,
+    };
+    let mut hash = StableHasher::default();
+    for key in keys {
+        let (entity, _) = streamed.loaded[key];
+        hash.write(&key.coord.to_array().map(|c| c.to_le_bytes()).concat());
+
+        let voxels = world.get::<CaveChunkVoxels>(entity).unwrap();
+        if let Some(data) = &*voxels.data.read().unwrap() {
+            let length = voxels.shape.as_array()[0];
+            for (i, voxel) in data.iter().enumerate() {
+                let p = voxels.shape.delinearize(i as u32);
+                let interior = p.iter().all(|p| *p > 0 && *p < length - 1);
+                if interior && *voxel != CaveVoxel::EMPTY {
+                    summary.voxels += 1;
+                }
+            }
+            hash.write(&data.iter().map(|voxel| voxel.0).collect::<Vec<_>>());
+        }
+
+        // Submeshes come in no particular order, so hash them separately.
+        let mut submesh_hashes = Vec::new();
+        for handle in chunk_meshes(world, entity) {
+            let mesh = meshes.get(&handle).unwrap();
+            let mut submesh_hash = StableHasher::default();
+            if let Some(Indices::U32(indices)) = mesh.indices() {
+                summary.triangles += indices.len() / 3;
+                for index in indices {
+                    submesh_hash.write(&index.to_le_bytes());
+                }
+            }
+            if let Some(VertexAttributeValues::Float32x3(positions)) =
+                mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+            {
+                // simdnoise picks its SIMD path at runtime, and they round
+                // differently in the last bits.
+                for position in positions {
+                    for p in position {
+                        let p = (p * POSITION_QUANTUM).round() as i32;
+                        submesh_hash.write(&p.to_le_bytes());
+                    }
+                }
+            }
+            submesh_hashes.push(submesh_hash.finish());
+        }
+        submesh_hashes.sort();
+        for submesh_hash in submesh_hashes {
+            hash.write(&submesh_hash.to_le_bytes());
+        }
+    }
+    summary.hash = hash.finish();
+    summary
+}
+
+fn chunk_meshes(world: &World, entity: Entity) -> Vec<Handle<Mesh>> {
+    let mut handles = Vec::new();
+    for child in world.get::<Children>(entity).into_iter().flatten() {
+        for grandchild in world.get::<Children>(*child).into_iter().flatten() {
+            handles.extend(world.get::<Handle<Mesh>>(*grandchild).cloned());
+        }
+    }
+    handles
+}
+
+// Update the references when generation or meshing changes on purpose.
+#[test]
+fn blocky_caves_match_reference() {
+    let summary = generate(CaveChunkSettings::default());
+    assert_eq!(
+        summary,
+        CaveSummary {
+            chunks: 18,
+            voxels: 13130,
+            triangles: 1350,
+            hash: 5614751138445900138,
+        }
+    );
+}
+
+#[test]
+fn smooth_caves_match_reference() {
+    let summary = generate(CaveChunkSettings {
+        mesh_mode: CaveMeshMode::Smooth,
+        ..default()
+    });
+    assert_eq!(
+        summary,
+        CaveSummary {
+            chunks: 18,
+            voxels: 13130,
+            triangles: 4782,
+            hash: 5730722775302114604,
+        }
+    );
+}
+
+#[test]
+fn generation_is_deterministic() {
+    let settings = CaveChunkSettings {
+        seed: 7,
+        ..default()
+    };
+    assert_eq!(generate(settings.clone()), generate(settings));
+}
+
+#[test]
+fn caves_above_threshold_are_empty() {
+    let summary = generate(CaveChunkSettings {
+        threshold: 10.0,
+        ..default()
+    });
+    assert_eq!(summary.voxels, 0);
+    assert_eq!(summary.triangles, 0);
+}
+
+#[test]
+fn solid_caves_are_stored_compactly() {
+    // Blocky meshes would still put faces on seams between resolutions.
+    let mut app = generated_app(CaveChunkSettings {
+        threshold: -10.0,
+        mesh_mode: CaveMeshMode::Smooth,
+        ..default()
+    });
+    let summary = summarize(&app.world);
+    assert_eq!(summary.triangles, 0);
+
+    let streamed = app.world.resource::<StreamedCaveChunks>();
+    for (entity, _) in streamed.loaded.values() {
+        let voxels = app.world.get::<CaveChunkVoxels>(*entity).unwrap();
+        assert!(matches!(voxels.fill, CaveChunkFill::Solid(_)));
+        assert!(voxels.data.read().unwrap().is_none());
+    }
+
+    let mut state = SystemState::<CaveWorld>::new(&mut app.world);
+    let cave_world = state.get(&app.world);
+    let hit = cave_world.raycast(Vec3::new(0.3, -0.5, 0.3), Vec3::X, 1.0);
+    assert_eq!(hit.map(|hit| hit.distance), Some(0.0));
+}
+
+#[test]
+fn raycast_finds_nearest_solid_voxel() {
+    let mut app = generated_app(CaveChunkSettings::default());
+    let mut state = SystemState::<CaveWorld>::new(&mut app.world);
+    let cave_world = state.get(&app.world);
+
+    let origin = Vec3::new(0.3, -0.5, 0.3);
+    let max_distance = 1.5;
+    let solids = cave_world.solids(
+        origin - Vec3::splat(max_distance),
+        origin + Vec3::splat(max_distance),
+    );
+
+    let mut hits = 0;
+    for i in 0..64 {
+        // Spread the rays over a sphere.
+        let y = 1.0 - (i as f32 + 0.5) / 32.0;
+        let angle = i as f32 * 2.399_963;
+        let r = (1.0 - y * y).sqrt();
+        let direction = Vec3::new(r * angle.cos(), y, r * angle.sin());
+
+        // Nearest entry into any solid voxel box, by slab test.
+        let expected = solids
+            .iter()
+            .filter_map(|(min, max)| {
+                let (a, b) = ((*min - origin) / direction, (*max - origin) / direction);
+                let (near, far) = (a.min(b).max_element(), a.max(b).min_element());
+                (near <= far && far >= 0.0).then_some(near.max(0.0))
+            })
+            .filter(|distance| *distance <= max_distance)
+            .min_by(f32::total_cmp);
+
+        let hit = cave_world.raycast(origin, direction, max_distance);
+        assert_eq!(hit.is_some(), expected.is_some(), "ray {i}");
+        if let (Some(hit), Some(expected)) = (hit, expected) {
+            assert!((hit.distance - expected).abs() < 1e-3, "ray {i}");
+            assert_ne!(hit.material, CaveVoxel::EMPTY.0);
+            assert!(hit.normal == IVec3::ZERO || hit.normal.as_vec3().dot(direction) < 0.0);
+            hits += 1;
+        }
+    }
+    assert!(hits > 0);
+}
+
+#[test]
+fn index_follows_streamed_chunks() {
+    let app = generated_app(CaveChunkSettings::default());
+    let streamed = app.world.resource::<StreamedCaveChunks>();
+    let index = app.world.resource::<CaveChunkIndex>();
+
+    assert_eq!(index.len(), streamed.loaded.len());
+    for (key, (entity, _)) in &streamed.loaded {
+        let entry = index.get(*key).unwrap();
+        assert_eq!(entry.entity, *entity);
+        assert_eq!(entry.stage, CaveChunkStage::Ready);
+        assert_eq!(index.key(*entity), Some(*key));
+
+        for (neighbour, _) in index.neighbours(*key) {
+            assert!(index
+                .neighbours(neighbour)
+                .iter()
+                .any(|(back, _)| back == key));
+        }
+    }
+}
+
+#[test]
+fn diagnostics_follow_the_pipeline() {
+    let app = generated_app(CaveChunkSettings::default());
+    let summary = summarize(&app.world);
+    let diagnostics = app.world.resource::<DiagnosticsStore>();
+    let value = |id| diagnostics.get_measurement(id).unwrap().value;
+
+    assert_eq!(value(CaveDiagnosticsPlugin::QUEUED), 0.0);
+    assert_eq!(value(CaveDiagnosticsPlugin::MESHING), 0.0);
+    assert_eq!(
+        value(CaveDiagnosticsPlugin::TRIANGLES),
+        summary.triangles as f64
+    );
+    assert!(value(CaveDiagnosticsPlugin::GENERATE_TIME) > 0.0);
+    assert!(value(CaveDiagnosticsPlugin::SAMPLE_MEMORY) > 0.0);
+    assert!(value(CaveDiagnosticsPlugin::VOXEL_MEMORY) > 0.0);
+}
+
+#[test]
+fn poisoned_voxels_count_as_not_voxelized() {
+    let mut app = headless_app(CaveChunkSettings {
+        threshold: -10.0,
+        ..default()
+    });
+    wait_for_chunks(&mut app);
+    let solid = Vec3::new(0.3, -0.5, 0.3);
+    assert!(solid_at(&mut app, solid));
+
+    let data = app
+        .world
+        .query::<&CaveChunkVoxels>()
+        .iter(&app.world)
+        .map(|voxels| voxels.data.clone())
+        .collect::<Vec<_>>();
+    for data in data {
+        let _ = thread::spawn(move || {
+            let _data = data.write().unwrap();
+            panic!("poisoning cave chunk voxels");
+        })
+        .join();
+    }
+
+    assert!(!solid_at(&mut app, solid));
+    let mut state = SystemState::<CaveWorld>::new(&mut app.world);
+    let cave_world = state.get(&app.world);
+    assert!(!cave_world.is_voxelized(solid));
+    assert!(cave_world.solids(solid - 1.0, solid + 1.0).is_empty());
+}
+
+#[test]
+fn edits_wait_for_chunks_to_be_generated() {
+    let mut app = headless_app(CaveChunkSettings {
+        threshold: -10.0,
+        ..default()
+    });
+    // Spawned and indexed, but not generated.
+    let max_jobs = std::mem::take(&mut app.world.resource_mut::<CaveScheduleSettings>().max_jobs);
+    app.update();
+    app.update();
+    let index = app.world.resource::<CaveChunkIndex>();
+    assert!(!index.is_empty());
+    assert!(index
+        .iter()
+        .all(|(_, entry)| entry.stage == CaveChunkStage::Generating));
+
+    let dug = Vec3::new(0.3, -0.5, 0.3);
+    app.world.send_event(CaveEdit {
+        position: dug,
+        shape: CaveEditShape::Sphere { radius: 0.4 },
+        action: CaveEditAction::Dig,
+    });
+    app.update();
+    assert!(!app.world.resource::<PendingCaveEdits>().is_empty());
+
+    app.world.resource_mut::<CaveScheduleSettings>().max_jobs = max_jobs;
+    wait_for_chunks(&mut app);
+    assert!(app.world.resource::<PendingCaveEdits>().is_empty());
+    assert!(!solid_at(&mut app, dug));
+}
+
+#[test]
+fn edits_to_locked_chunks_are_retried() {
+    let mut app = headless_app(CaveChunkSettings {
+        threshold: -10.0,
+        ..default()
+    });
+    wait_for_chunks(&mut app);
+
+    let dug = Vec3::new(0.3, -0.5, 0.3);
+    let samples: Vec<_> = app
+        .world
+        .query::<&CaveChunk>()
+        .iter(&app.world)
+        .map(|cave_chunk| cave_chunk.samples.clone())
+        .collect();
+    let guards: Vec<_> = samples
+        .iter()
+        .map(|samples| samples.read().unwrap())
+        .collect();
+    app.world.send_event(CaveEdit {
+        position: dug,
+        shape: CaveEditShape::Sphere { radius: 0.4 },
+        action: CaveEditAction::Dig,
+    });
+    for _ in 0..3 {
+        app.update();
+    }
+    assert!(!app.world.resource::<PendingCaveEdits>().is_empty());
+
+    drop(guards);
+    wait_for_chunks(&mut app);
+    assert!(app.world.resource::<PendingCaveEdits>().is_empty());
+    assert!(!solid_at(&mut app, dug));
+}
+
+#[test]
+fn edits_survive_reloading_at_another_resolution() {
+    let dir = env::temp_dir().join(format!("voxels-edit-reload-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut app = headless_app(CaveChunkSettings {
+        threshold: -10.0,
+        ..default()
+    });
+    app.insert_resource(CaveRegionStore::new(&dir));
+    wait_for_chunks(&mut app);
+
+    let dug = Vec3::new(0.3, -0.5, 0.3);
+    let untouched = Vec3::new(1.0, -0.5, 0.3);
+    assert!(solid_at(&mut app, dug));
+    app.world.send_event(CaveEdit {
+        position: dug,
+        shape: CaveEditShape::Sphere { radius: 0.4 },
+        action: CaveEditAction::Dig,
+    });
+    wait_for_chunks(&mut app);
+    assert!(!solid_at(&mut app, dug));
+
+    // Changing the resolution streams every chunk out and back in.
+    for subdivisions in [3, 4] {
+        app.world
+            .resource_mut::<CaveStreamSettings>()
+            .max_subdivisions = subdivisions;
+        wait_for_chunks(&mut app);
+        let streamed = app.world.resource::<StreamedCaveChunks>();
+        assert!(streamed
+            .loaded
+            .values()
+            .all(|(_, detail)| detail.subdivisions == subdivisions));
+        assert!(!solid_at(&mut app, dug), "{subdivisions} subdivisions");
+        assert!(solid_at(&mut app, untouched), "{subdivisions} subdivisions");
+    }
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn failed_chunks_back_off_and_give_up() {
+    let mut app = generated_app(CaveChunkSettings::default());
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs(1)));
+
+    // A panic while holding the samples fails every remesh of the chunk.
+    let streamed = app.world.resource::<StreamedCaveChunks>();
+    let (entity, _) = *streamed.loaded.values().next().unwrap();
+    let samples = app.world.get::<CaveChunk>(entity).unwrap().samples.clone();
+    let _ = thread::spawn(move || {
+        let _samples = samples.write().unwrap();
+        panic!("poisoning the chunk samples");
+    })
+    .join();
+    app.world
+        .resource_mut::<CaveChunkScheduler>()
+        .remesh(entity);
+
+    let deadline = Instant::now() + JOB_TIMEOUT;
+    let mut backed_off = false;
+    while Instant::now() < deadline {
+        app.update();
+        let scheduler = app.world.resource::<CaveChunkScheduler>();
+        if scheduler.failed_len() > 0 {
+            backed_off = true;
+            assert_eq!(
+                app.world.get::<CaveChunkStage>(entity),
+                Some(&CaveChunkStage::Failed)
+            );
+        } else if backed_off
+            && scheduler.queued_len() == 0
+            && scheduler.running_stages().next().is_none()
+        {
+            break;
+        }
+        thread::yield_now();
+    }
+    assert!(backed_off);
+
+    // Given up on, but still streamed in rather than spawned again.
+    for _ in 0..10 {
+        app.update();
+    }
+    let scheduler = app.world.resource::<CaveChunkScheduler>();
+    assert_eq!(scheduler.failed_len(), 0);
+    assert_eq!(scheduler.queued_len(), 0);
+    assert_eq!(
+        app.world.get::<CaveChunkStage>(entity),
+        Some(&CaveChunkStage::Failed)
+    );
+    let streamed = app.world.resource::<StreamedCaveChunks>();
+    assert!(streamed
+        .loaded
+        .values()
+        .any(|(loaded, _)| *loaded == entity));
+}
+
+#[test]
+fn only_generation_settings_regenerate_chunks() {
+    let mut app = generated_app(CaveChunkSettings::default());
+    let entities = |app: &App| {
+        let streamed = app.world.resource::<StreamedCaveChunks>();
+        let mut entities: Vec<_> = streamed.loaded.values().map(|(e, _)| *e).collect();
+        entities.sort();
+        entities
+    };
+    let before = entities(&app);
+
+    // Switching mesh modes remeshes the chunks in place.
+    app.world.resource_mut::<CaveChunkSettings>().mesh_mode = CaveMeshMode::Smooth;
+    wait_for_chunks(&mut app);
+    assert_eq!(entities(&app), before);
+    assert_eq!(summarize(&app.world).triangles, 4782);
+
+    app.world.resource_mut::<CaveChunkSettings>().seed += 1;
+    wait_for_chunks(&mut app);
+    assert!(entities(&app).iter().all(|entity| !before.contains(entity)));
+}
+
+#[test]
+fn seam_changes_remesh_chunks_in_place() {
+    let mut app = headless_app(CaveChunkSettings::default());
+    // Resolution drops a chunk or two away, so moving changes seams.
+    app.world
+        .resource_mut::<CaveStreamSettings>()
+        .subdivision_falloff = 0.5;
+    wait_for_chunks(&mut app);
+    let before = app.world.resource::<StreamedCaveChunks>().loaded.clone();
+
+    let player = app
+        .world
+        .query_filtered::<Entity, With<Player>>()
+        .single(&app.world);
+    // Streaming follows the global transform, which nothing propagates here.
+    *app.world.get_mut::<GlobalTransform>(player).unwrap() =
+        GlobalTransform::from_translation(Vec3::X * 1.28);
+    wait_for_chunks(&mut app);
+
+    let streamed = app.world.resource::<StreamedCaveChunks>();
+    let mut remeshed = 0;
+    for (key, (entity, detail)) in &streamed.loaded {
+        let (previous, previous_detail) = if let Some(loaded) = before.get(key) {
+            loaded
+        } else {
+            continue;
+        };
+        if detail.subdivisions == previous_detail.subdivisions {
+            assert_eq!(entity, previous, "{key:?}");
+            let cave_chunk = app.world.get::<CaveChunk>(*entity).unwrap();
+            assert_eq!(cave_chunk.seams, detail.seams, "{key:?}");
+            remeshed += (detail.seams != previous_detail.seams) as usize;
+        }
+    }
+    assert!(remeshed > 0);
+}
+
+#[test]
+fn replaced_chunks_stay_until_their_replacement_is_ready() {
+    let mut app = headless_app(CaveChunkSettings::default());
+    app.world
+        .resource_mut::<CaveStreamSettings>()
+        .subdivision_falloff = 0.5;
+    wait_for_chunks(&mut app);
+    let before = app.world.resource::<StreamedCaveChunks>().loaded.clone();
+
+    let player = app
+        .world
+        .query_filtered::<Entity, With<Player>>()
+        .single(&app.world);
+    *app.world.get_mut::<GlobalTransform>(player).unwrap() =
+        GlobalTransform::from_translation(Vec3::X * 2.56);
+    app.update();
+
+    let streamed = app.world.resource::<StreamedCaveChunks>();
+    let mut replaced = Vec::new();
+    for (key, (_, detail)) in &streamed.loaded {
+        let Some((previous, previous_detail)) = before.get(key) else {
+            continue;
+        };
+        if detail.subdivisions != previous_detail.subdivisions {
+            assert_eq!(streamed.replaced.get(key), Some(previous), "{key:?}");
+            replaced.push(*previous);
+        }
+    }
+    assert!(!replaced.is_empty());
+    for entity in &replaced {
+        assert!(app.world.get::<CaveChunk>(*entity).is_some());
+    }
+
+    wait_for_chunks(&mut app);
+    app.update();
+    assert!(app
+        .world
+        .resource::<StreamedCaveChunks>()
+        .replaced
+        .is_empty());
+    for entity in &replaced {
+        assert!(app.world.get_entity(*entity).is_none());
+    }
+}
+
+/// A floor with its top at y = -1 and `boxes` of rock, as centres and half
+/// extents, around a player in `mode` standing at `feet`, stepped at 60 fps.
+fn walk_app(boxes: &[(Vec3, Vec3)], mode: PlayerMode, feet: Vec3) -> App {
+    let generator = boxes.iter().fold(
+        CsgGenerator::new(NoiseGenerator).with(
+            CsgOp::Union,
+            CaveEditShape::Box {
+                half_extents: Vec3::new(4.0, 0.25, 4.0),
+            },
+            Vec3::new(0.0, -1.25, 0.0),
+            1,
+        ),
+        |generator, (centre, half_extents)| {
+            generator.with(
+                CsgOp::Union,
+                CaveEditShape::Box {
+                    half_extents: *half_extents,
+                },
+                *centre,
+                1,
+            )
+        },
+    );
+    let mut app = headless_app(CaveChunkSettings {
+        generator: "walk".into(),
+        // No rock but the boxes.
+        threshold: 10.0,
+        ..default()
+    });
+    app.add_plugins(PlayerPlugin)
+        .add_cave_generator("walk", generator)
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+            1.0 / 60.0,
+        )));
+
+    let settings = WalkSettings::default();
+    let player = player(&mut app);
+    app.world
+        .entity_mut(player)
+        .insert(TransformBundle::from_transform(
+            Transform::from_translation(feet + Vec3::Y * (settings.height - settings.eye_offset)),
+        ));
+    wait_for_chunks(&mut app);
+    // Controlled only now, so the player hasn't moved while the chunks loaded.
+    app.world.entity_mut(player).insert((
+        mode,
+        CharacterController::default(),
+        CameraController {
+            translate: mode == PlayerMode::Fly,
+            ..default()
+        },
+    ));
+    app
+}
+
+fn player(app: &mut App) -> Entity {
+    app.world
+        .query_filtered::<Entity, With<Player>>()
+        .single(&app.world)
+}
+
+/// Runs `frames` frames wishing to move in `direction`, returning where the
+/// player's feet end up.
+fn walk_for(app: &mut App, frames: usize, direction: Vec3) -> Vec3 {
+    for _ in 0..frames {
+        app.world.send_event(CameraControlEvent {
+            delta_rotation: Vec2::ZERO,
+            delta_translation: direction,
+            sprint: false,
+        });
+        app.update();
+    }
+    feet(app)
+}
+
+fn feet(app: &mut App) -> Vec3 {
+    let player = player(app);
+    let settings = app.world.resource::<WalkSettings>();
+    let height = if app
+        .world
+        .get::<CharacterController>(player)
+        .unwrap()
+        .crouching
+    {
+        settings.crouch_height
+    } else {
+        settings.height
+    };
+    app.world.get::<Transform>(player).unwrap().translation
+        - Vec3::Y * (height - settings.eye_offset)
+}
+
+#[test]
+fn walking_players_fall_onto_the_floor() {
+    let mut app = walk_app(&[], PlayerMode::Walk, Vec3::new(0.3, 0.5, 0.3));
+    let start = feet(&mut app);
+
+    let falling = walk_for(&mut app, 10, Vec3::ZERO);
+    assert!(falling.y < start.y);
+    let player = player(&mut app);
+    assert!(
+        app.world
+            .get::<CharacterController>(player)
+            .unwrap()
+            .velocity
+            .y
+            < 0.0
+    );
+
+    let landed = walk_for(&mut app, 120, Vec3::ZERO);
+    assert!((landed.y + 1.0).abs() < 0.1, "{landed}");
+    let controller = app.world.get::<CharacterController>(player).unwrap();
+    assert!(controller.grounded);
+    assert_eq!(controller.velocity.y, 0.0);
+}
+
+#[test]
+fn walking_players_step_onto_low_ledges_only() {
+    // Ledges from x = 0.6 onwards, lower than and taller than the step height.
+    for (height, climbs) in [(0.16, true), (0.5, false)] {
+        let ledge = (
+            Vec3::new(1.1, -1.0 + height / 2.0, 0.3),
+            Vec3::new(0.5, height / 2.0, 1.0),
+        );
+        let mut app = walk_app(&[ledge], PlayerMode::Walk, Vec3::new(0.0, -1.0, 0.3));
+        walk_for(&mut app, 10, Vec3::ZERO);
+
+        let feet = walk_for(&mut app, 30, Vec3::X);
+        if climbs {
+            assert!(feet.x > 0.9, "{feet}");
+            assert!((feet.y - (-1.0 + height)).abs() < 0.1, "{feet}");
+        } else {
+            assert!(feet.x < 0.35, "{feet}");
+            assert!((feet.y + 1.0).abs() < 0.1, "{feet}");
+        }
+    }
+}
+
+#[test]
+fn low_ceilings_keep_players_crouched() {
+    // Too low to stand under, but high enough to crouch under.
+    let ceiling = (Vec3::new(0.0, 0.65, -0.5), Vec3::new(2.0, 0.25, 0.5));
+    let mut app = walk_app(&[ceiling], PlayerMode::Walk, Vec3::new(0.3, -1.0, 1.0));
+    walk_for(&mut app, 10, Vec3::ZERO);
+    let player = player(&mut app);
+    let crouching = |app: &App| {
+        app.world
+            .get::<CharacterController>(player)
+            .unwrap()
+            .crouching
+    };
+
+    let feet = walk_for(&mut app, 60, Vec3::new(0.0, -1.0, -1.0));
+    assert!(crouching(&app));
+    assert!(feet.z < -0.4, "{feet}");
+
+    walk_for(&mut app, 10, Vec3::ZERO);
+    assert!(crouching(&app), "stood up under the ceiling");
+
+    let feet = walk_for(&mut app, 60, Vec3::Z);
+    assert!(feet.z > 0.4, "{feet}");
+    assert!(!crouching(&app));
+}
+
+#[test]
+fn toggle_walk_switches_between_flying_and_walking() {
+    let mut app = walk_app(&[], PlayerMode::Fly, Vec3::new(0.3, 0.5, 0.3));
+    app.insert_resource(Controls::default())
+        .add_plugins((InputPlugin, ControlsPlugin));
+    let player = player(&mut app);
+    let toggle = |app: &mut App| {
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::F);
+        app.update();
+        app.world
+            .resource_mut::<Input<KeyCode>>()
+            .release(KeyCode::F);
+        app.update();
+    };
+
+    // Flying players stay where they are.
+    let start = walk_for(&mut app, 30, Vec3::ZERO);
+    assert_eq!(start, feet(&mut app));
+    assert!((start.y - 0.5).abs() < 1e-4, "{start}");
+
+    toggle(&mut app);
+    assert_eq!(app.world.get::<PlayerMode>(player), Some(&PlayerMode::Walk));
+    assert!(!app.world.get::<CameraController>(player).unwrap().translate);
+    let landed = walk_for(&mut app, 120, Vec3::ZERO);
+    assert!((landed.y + 1.0).abs() < 0.1, "{landed}");
+
+    toggle(&mut app);
+    assert_eq!(app.world.get::<PlayerMode>(player), Some(&PlayerMode::Fly));
+    assert!(app.world.get::<CameraController>(player).unwrap().translate);
+    assert_eq!(walk_for(&mut app, 30, Vec3::ZERO), landed);
+}
@@ -0,0 +1,208 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use voxels::cave::{
+    chunk::{CaveChunk, CaveChunkFill, CaveChunkSettings, CavePaletteEntry},
+    edit::CaveEditShape,
+    generator::{CsgGenerator, CsgOp, NoiseGenerator},
+    region::{fingerprint, CaveRegionStore},
+    stream::CaveChunkKey,
+};
+
+const SUBDIVISIONS: u32 = 3;
+
+fn store_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("voxels-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// A chunk of one density and palette id throughout.
+fn uniform_chunk(settings: &CaveChunkSettings, density: f32, material: u8) -> CaveChunk {
+    let len = (2_usize.pow(SUBDIVISIONS) + 2).pow(3);
+    CaveChunk::from_samples(
+        settings,
+        SUBDIVISIONS,
+        0,
+        vec![density; len],
+        vec![material; len],
+    )
+}
+
+fn fill(store: &CaveRegionStore, settings: &CaveChunkSettings, key: CaveChunkKey) -> CaveChunkFill {
+    fill_at(store, settings, key, SUBDIVISIONS)
+}
+
+fn fill_at(
+    store: &CaveRegionStore,
+    settings: &CaveChunkSettings,
+    key: CaveChunkKey,
+    subdivisions: u32,
+) -> CaveChunkFill {
+    let cave_chunk = store
+        .load(settings, &NoiseGenerator, key, subdivisions, 0)
+        .unwrap()
+        .unwrap();
+    let fill = cave_chunk.samples.read().unwrap().fill();
+    fill
+}
+
+#[test]
+fn regions_survive_flush_and_eviction() {
+    let dir = store_dir("region-flush");
+    // Materials are stored by name.
+    let settings = CaveChunkSettings {
+        palette: vec![CavePaletteEntry {
+            name: "rock".into(),
+            weight: 1.0,
+            material: default(),
+        }],
+        ..default()
+    };
+    let key = CaveChunkKey {
+        lod: 0,
+        coord: IVec3::new(-3, 2, 9),
+    };
+    let solid = uniform_chunk(&settings, settings.threshold + 1.0, 1);
+    let empty = uniform_chunk(&settings, settings.threshold - 1.0, 0);
+
+    let store = CaveRegionStore::new(&dir);
+    store.save_generated(&NoiseGenerator, key, &solid).unwrap();
+    store.flush().unwrap();
+    assert_eq!(
+        fill(&CaveRegionStore::new(&dir), &settings, key),
+        CaveChunkFill::Solid(1)
+    );
+
+    // Changes not written yet keep their region in memory.
+    store.save_edited(&NoiseGenerator, key, &empty).unwrap();
+    store.evict(&settings, &NoiseGenerator, []).unwrap();
+    assert_eq!(fill(&store, &settings, key), CaveChunkFill::Empty);
+
+    // Written regions are read back after being evicted.
+    store.flush().unwrap();
+    store.evict(&settings, &NoiseGenerator, []).unwrap();
+    assert_eq!(fill(&store, &settings, key), CaveChunkFill::Empty);
+
+    // Generated chunks never replace an edit, at any resolution.
+    store.save_generated(&NoiseGenerator, key, &solid).unwrap();
+    assert_eq!(fill(&store, &settings, key), CaveChunkFill::Empty);
+    assert_eq!(
+        fill_at(&store, &settings, key, SUBDIVISIONS + 1),
+        CaveChunkFill::Empty
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn regions_are_kept_per_generator() {
+    let dir = store_dir("region-generator");
+    let settings = CaveChunkSettings::default();
+    let key = CaveChunkKey {
+        lod: 0,
+        coord: IVec3::ZERO,
+    };
+    let chamber = |radius| {
+        CsgGenerator::new(NoiseGenerator).with(
+            CsgOp::Subtract,
+            CaveEditShape::Sphere { radius },
+            Vec3::ZERO,
+            0,
+        )
+    };
+
+    let store = CaveRegionStore::new(&dir);
+    let cave_chunk = uniform_chunk(&settings, settings.threshold - 1.0, 0);
+    store
+        .save_generated(&chamber(2.0), key, &cave_chunk)
+        .unwrap();
+    let load = |generator: &CsgGenerator| {
+        store
+            .load(&settings, generator, key, SUBDIVISIONS, 0)
+            .unwrap()
+            .is_some()
+    };
+    assert!(load(&chamber(2.0)));
+    assert!(!load(&chamber(3.0)));
+    assert!(store
+        .load(&settings, &NoiseGenerator, key, SUBDIVISIONS, 0)
+        .unwrap()
+        .is_none());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn fingerprints_keep_neighbouring_strings_apart() {
+    let mut settings = CaveChunkSettings {
+        generator: "noisex".into(),
+        palette: vec![CavePaletteEntry {
+            name: "y".into(),
+            weight: 1.0,
+            material: default(),
+        }],
+        ..default()
+    };
+    let joined = fingerprint(&settings, &NoiseGenerator);
+
+    settings.generator = "noise".into();
+    settings.palette[0].name = "xy".into();
+    assert_ne!(fingerprint(&settings, &NoiseGenerator), joined);
+}
+
+#[test]
+fn unreadable_regions_are_moved_aside() {
+    let dir = store_dir("region-unreadable");
+    let settings = CaveChunkSettings::default();
+    let key = CaveChunkKey {
+        lod: 0,
+        coord: IVec3::ZERO,
+    };
+    let cave_chunk = uniform_chunk(&settings, settings.threshold - 1.0, 0);
+
+    let store = CaveRegionStore::new(&dir);
+    store
+        .save_generated(&NoiseGenerator, key, &cave_chunk)
+        .unwrap();
+    store.flush().unwrap();
+    let path = region_files(&dir).pop().unwrap();
+    fs::write(&path, b"not a region").unwrap();
+
+    let store = CaveRegionStore::new(&dir);
+    assert!(store
+        .load(&settings, &NoiseGenerator, key, SUBDIVISIONS, 0)
+        .unwrap()
+        .is_none());
+    store
+        .save_generated(&NoiseGenerator, key, &cave_chunk)
+        .unwrap();
+    store.flush().unwrap();
+
+    assert_eq!(
+        fs::read(path.with_extension("region.bad")).unwrap(),
+        b"not a region"
+    );
+    assert!(CaveRegionStore::new(&dir)
+        .load(&settings, &NoiseGenerator, key, SUBDIVISIONS, 0)
+        .unwrap()
+        .is_some());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+fn region_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(region_files(&path));
+        } else if path.extension().is_some_and(|e| e == "region") {
+            files.push(path);
+        }
+    }
+    files
+}